@@ -1,4 +1,4 @@
-use deafen::models::{RegistrationRequest, RegistrationResponse, ScanRequest, UTXO};
+use deafen::models::{MatchedOutput, RegistrationRequest, RegistrationResponse, ScanRequest};
 use reqwest::Client;
 
 #[tokio::main]
@@ -19,6 +19,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .to_string(),
         network: "testnet".to_string(),
         b_scan: "04b2a411635c097759aacd0f005a4c82c8c92862c6fc284b80b8efebc20c3d17".to_string(),
+        birthday_height: 0,
+        webhook_url: None,
+        labels: vec![],
     };
 
     let registration_response: RegistrationResponse = client
@@ -36,14 +39,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         registration_response.receiving_address
     );
 
-    // Query UTXOs
+    // Query UTXOs, authenticating with the token issued at registration
+    let api_token = registration_response.api_token;
     let query_request = ScanRequest {
         block_height: 1,
         client_id: registration_response.client_id,
+        unspent_only: true,
+        with_spent_info: false,
     };
 
-    let utxos: Vec<UTXO> = client
+    let utxos: Vec<MatchedOutput> = client
         .post(&format!("{}/query", base_url))
+        .bearer_auth(&api_token)
         .json(&query_request)
         .send()
         .await?
@@ -54,13 +61,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     if utxos.is_empty() {
         println!("No UTXOs found.");
     } else {
-        for (i, utxo) in utxos.iter().enumerate() {
+        for (i, MatchedOutput { utxo, label }) in utxos.iter().enumerate() {
             println!("UTXO {}:", i + 1);
             println!("  TXID: {:?}", utxo.txid);
             println!("  VOUT: {}", utxo.vout);
             println!("  Amount: {}", utxo.amount);
             println!("  Script Pubkey: {:?}", utxo.script_pubkey);
             println!("  Input Tweak: {:?}", utxo.input_tweak);
+            if let Some(label) = label {
+                println!("  Label: {}", label);
+            }
         }
     }
 
@@ -5,11 +5,30 @@ use crate::{
     Error,
 };
 use crate::{
-    models::{RegistrationRequest, ScanRequest, TweakRequest},
+    models::{
+        BlockFilter, BlockFilterResponse, BlockTweaks, BlockTweaksQuery, ClientEvent, ClientOutput,
+        ClientScanQuery, ClientUpdateRequest, FoundOutputsQuery, MatchedOutput,
+        RegistrationRequest, ScanRequest, TweakFormat, TweakRequest, TweakResponse,
+        TweakStreamEvent, TweakStreamQuery, UtxoWithSpend, UTXO,
+    },
     services::{ClientService, ScanService},
 };
+use bitcoin::hashes::Hash;
+use bitcoin::{BlockHash, Txid};
+use futures_util::{SinkExt, StreamExt};
+use std::str::FromStr;
 use std::sync::Arc;
-use warp::{http::StatusCode, reply::json, Reply};
+use tokio::sync::broadcast::{self, error::RecvError};
+use warp::{
+    http::{header, HeaderValue, StatusCode},
+    reply::{json, Response},
+    sse::Event,
+    ws::{Message, WebSocket, Ws},
+    Reply,
+};
+
+/// Page size of found outputs when the client does not pick one.
+const DEFAULT_OUTPUTS_PAGE: usize = 100;
 
 pub async fn handle_query<
     S: UtxoStore + ClientStore + Send + Sync + 'static,
@@ -18,11 +37,60 @@ pub async fn handle_query<
     query: ScanRequest,
     scan_service: Arc<ScanService<S, C>>,
 ) -> Result<impl Reply, warp::Rejection> {
-    let utxos = scan_service
+    let with_spent_info = query.with_spent_info;
+    let outputs = scan_service
         .scan_utxos(query)
         .await
         .map_err(warp::reject::custom)?;
-    Ok(json(&utxos))
+    Ok(outputs_reply(outputs, with_spent_info))
+}
+
+pub async fn handle_scan_new_blocks<
+    S: UtxoStore + ClientStore + Send + Sync + 'static,
+    C: Compute + 'static,
+>(
+    client_id: String,
+    query: ClientScanQuery,
+    scan_service: Arc<ScanService<S, C>>,
+) -> Result<impl Reply, warp::Rejection> {
+    let response = scan_service
+        .scan_new_blocks(&client_id, query.unspent_only)
+        .await
+        .map_err(warp::reject::custom)?;
+    Ok(json(&response))
+}
+
+pub async fn handle_client_outputs<
+    S: UtxoStore + ClientStore + Send + Sync + 'static,
+    C: Compute + 'static,
+>(
+    client_id: String,
+    query: FoundOutputsQuery,
+    scan_service: Arc<ScanService<S, C>>,
+) -> Result<impl Reply, warp::Rejection> {
+    let page = scan_service
+        .client_outputs(
+            &client_id,
+            query.after.as_deref(),
+            query.limit.unwrap_or(DEFAULT_OUTPUTS_PAGE),
+        )
+        .await
+        .map_err(warp::reject::custom)?;
+    Ok(json(&page))
+}
+
+pub async fn handle_client_balance<
+    S: UtxoStore + ClientStore + Send + Sync + 'static,
+    C: Compute + 'static,
+>(
+    client_id: String,
+    scan_service: Arc<ScanService<S, C>>,
+) -> Result<impl Reply, warp::Rejection> {
+    let balance = scan_service
+        .client_balance(&client_id)
+        .await
+        .map_err(warp::reject::custom)?;
+    Ok(json(&balance))
 }
 
 pub async fn handle_tweak<
@@ -32,11 +100,177 @@ pub async fn handle_tweak<
     tweak_request: TweakRequest,
     scan_service: Arc<ScanService<S, C>>,
 ) -> Result<impl Reply, warp::Rejection> {
-    let utxos = scan_service
+    let with_spent_info = tweak_request.with_spent_info;
+    let cut_through = tweak_request.cut_through;
+    let response = scan_service
         .get_tweaks(tweak_request)
         .await
         .map_err(warp::reject::custom)?;
-    Ok(json(&utxos))
+    // Without cut-through the reply keeps its original shape, a bare array
+    let reply = match (cut_through, with_spent_info) {
+        (false, true) => json(&response.utxos),
+        (false, false) => json(&plain_utxos(response.utxos)),
+        (true, true) => json(&response),
+        (true, false) => json(&TweakResponse {
+            utxos: plain_utxos(response.utxos),
+            cut_through_omitted: response.cut_through_omitted,
+        }),
+    };
+    Ok(reply)
+}
+
+fn plain_utxos(utxos: Vec<UtxoWithSpend>) -> Vec<UTXO> {
+    utxos.into_iter().map(|u| u.utxo).collect()
+}
+
+pub async fn handle_block_tweaks<
+    S: UtxoStore + ClientStore + Send + Sync + 'static,
+    C: Compute + 'static,
+>(
+    block_height: u64,
+    query: BlockTweaksQuery,
+    scan_service: Arc<ScanService<S, C>>,
+) -> Result<Response, warp::Rejection> {
+    let tweaks = scan_service
+        .get_block_tweaks(block_height, query.cut_through)
+        .await
+        .map_err(warp::reject::custom)?;
+    match query.format {
+        TweakFormat::Hex => Ok(json(&BlockTweaks {
+            height: block_height,
+            tweaks: tweaks.iter().map(hex::encode).collect(),
+        })
+        .into_response()),
+        TweakFormat::Binary => Ok(warp::reply::with_header(
+            tweaks.concat(),
+            "content-type",
+            "application/octet-stream",
+        )
+        .into_response()),
+    }
+}
+
+pub async fn handle_tweak_stream<
+    S: UtxoStore + ClientStore + Send + Sync + 'static,
+    C: Compute + 'static,
+>(
+    last_event_id: Option<String>,
+    query: TweakStreamQuery,
+    scan_service: Arc<ScanService<S, C>>,
+) -> Result<impl Reply, warp::Rejection> {
+    let last_block = last_event_id
+        .map(|id| {
+            parse_event_id(&id).ok_or_else(|| {
+                warp::reject::custom(Error::InvalidInput(format!(
+                    "invalid Last-Event-ID: {}",
+                    id
+                )))
+            })
+        })
+        .transpose()?;
+    let events = scan_service
+        .tweak_stream(last_block, query.cut_through)
+        .await
+        .map_err(warp::reject::custom)?
+        .map(|event| {
+            let event = event?;
+            Ok::<_, Error>(
+                Event::default()
+                    .id(event.id())
+                    .event(match event {
+                        TweakStreamEvent::Block { .. } => "block",
+                        TweakStreamEvent::RolledBack { .. } => "rolled_back",
+                    })
+                    .data(serde_json::to_string(&event)?),
+            )
+        });
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)))
+}
+
+/// Parses a tweak stream event id, `height:block_hash` with the hash in RPC
+/// byte order.
+fn parse_event_id(id: &str) -> Option<(u64, [u8; 32])> {
+    let (height, block_hash) = id.split_once(':')?;
+    let block_hash = BlockHash::from_str(block_hash).ok()?;
+    Some((height.parse().ok()?, block_hash.to_byte_array()))
+}
+
+pub async fn handle_utxo<
+    S: UtxoStore + ClientStore + Send + Sync + 'static,
+    C: Compute + 'static,
+>(
+    txid: String,
+    vout: u32,
+    scan_service: Arc<ScanService<S, C>>,
+) -> Result<impl Reply, warp::Rejection> {
+    // The txid is given in RPC byte order but stored in internal byte order
+    let txid = Txid::from_str(&txid)
+        .map_err(|e| warp::reject::custom(Error::InvalidInput(format!("invalid txid: {}", e))))?;
+    let output = scan_service
+        .get_utxo(txid.to_byte_array(), vout)
+        .await
+        .map_err(warp::reject::custom)?
+        .ok_or_else(warp::reject::not_found)?;
+    Ok(json(&output))
+}
+
+pub async fn handle_filter<
+    S: UtxoStore + ClientStore + Send + Sync + 'static,
+    C: Compute + 'static,
+>(
+    block_height: u64,
+    scan_service: Arc<ScanService<S, C>>,
+) -> Result<impl Reply, warp::Rejection> {
+    let filter = scan_service
+        .get_filter(block_height)
+        .await
+        .map_err(warp::reject::custom)?
+        .ok_or_else(warp::reject::not_found)?;
+    Ok(json(&filter_response(block_height, filter)))
+}
+
+pub async fn handle_filters<
+    S: UtxoStore + ClientStore + Send + Sync + 'static,
+    C: Compute + 'static,
+>(
+    start_height: u64,
+    end_height: u64,
+    scan_service: Arc<ScanService<S, C>>,
+) -> Result<impl Reply, warp::Rejection> {
+    let filters = scan_service
+        .get_filters(start_height, end_height)
+        .await
+        .map_err(warp::reject::custom)?;
+    let filters: Vec<_> = filters
+        .into_iter()
+        .map(|(height, filter)| filter_response(height, filter))
+        .collect();
+    Ok(json(&filters))
+}
+
+fn filter_response(height: u64, filter: BlockFilter) -> BlockFilterResponse {
+    BlockFilterResponse {
+        height,
+        block_hash: BlockHash::from_byte_array(filter.block_hash).to_string(),
+        filter: hex::encode(filter.content),
+    }
+}
+
+/// Replies with the UTXOs and their labels, and with their heights and
+/// spends only if the client asked for them.
+fn outputs_reply(outputs: Vec<ClientOutput>, with_spent_info: bool) -> warp::reply::Json {
+    if with_spent_info {
+        json(&outputs)
+    } else {
+        let outputs: Vec<_> = outputs
+            .into_iter()
+            .map(|output| MatchedOutput {
+                utxo: output.output.utxo,
+                label: output.label,
+            })
+            .collect();
+        json(&outputs)
+    }
 }
 
 pub async fn handle_register<S: ClientStore + Send + Sync + 'static>(
@@ -50,12 +284,139 @@ pub async fn handle_register<S: ClientStore + Send + Sync + 'static>(
     Ok(json(&response))
 }
 
+pub async fn authorize<S: ClientStore + Send + Sync + 'static>(
+    client_id: String,
+    api_token: String,
+    client_service: Arc<ClientService<S>>,
+) -> Result<String, warp::Rejection> {
+    client_service
+        .authenticate(&client_id, &api_token)
+        .await
+        .map_err(warp::reject::custom)?;
+    Ok(client_id)
+}
+
+pub async fn authorize_query<S: ClientStore + Send + Sync + 'static>(
+    query: ScanRequest,
+    api_token: String,
+    client_service: Arc<ClientService<S>>,
+) -> Result<ScanRequest, warp::Rejection> {
+    client_service
+        .authenticate(&query.client_id, &api_token)
+        .await
+        .map_err(warp::reject::custom)?;
+    Ok(query)
+}
+
+pub async fn handle_get_client<S: ClientStore + Send + Sync + 'static>(
+    client_id: String,
+    client_service: Arc<ClientService<S>>,
+) -> Result<impl Reply, warp::Rejection> {
+    let info = client_service
+        .get_client_info(&client_id)
+        .await
+        .map_err(warp::reject::custom)?;
+    Ok(json(&info))
+}
+
+pub async fn handle_update_client<S: ClientStore + Send + Sync + 'static>(
+    client_id: String,
+    update: ClientUpdateRequest,
+    client_service: Arc<ClientService<S>>,
+) -> Result<impl Reply, warp::Rejection> {
+    let info = client_service
+        .update_client(&client_id, update)
+        .await
+        .map_err(warp::reject::custom)?;
+    Ok(json(&info))
+}
+
+pub async fn handle_delete_client<S: ClientStore + Send + Sync + 'static>(
+    client_id: String,
+    client_service: Arc<ClientService<S>>,
+) -> Result<impl Reply, warp::Rejection> {
+    client_service
+        .delete_client(&client_id)
+        .await
+        .map_err(warp::reject::custom)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Subprotocol selected for notification sockets when the client offers it.
+pub const WS_PROTOCOL: &str = "deafen";
+/// Prefix of the subprotocol a browser passes its API token in.
+pub const WS_TOKEN_PROTOCOL_PREFIX: &str = "deafen.bearer.";
+
+pub async fn handle_client_ws<S: ClientStore + Send + Sync + 'static>(
+    client_id: String,
+    ws: Ws,
+    protocols: Option<String>,
+    client_service: Arc<ClientService<S>>,
+) -> Result<impl Reply, warp::Rejection> {
+    // Subscribe before upgrading, so nothing sent in between is missed
+    let notifications = client_service.subscribe();
+    let mut response = ws
+        .on_upgrade(move |socket| push_notifications(socket, client_id, notifications))
+        .into_response();
+    // Browsers drop the connection unless one of their subprotocols is
+    // selected. The token subprotocol is never echoed back.
+    if protocols.is_some_and(|protocols| {
+        protocols
+            .split(',')
+            .any(|protocol| protocol.trim() == WS_PROTOCOL)
+    }) {
+        response.headers_mut().insert(
+            header::SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static(WS_PROTOCOL),
+        );
+    }
+    Ok(response)
+}
+
+/// Forwards the client's notifications to its socket as JSON text messages
+/// until either side goes away.
+async fn push_notifications(
+    socket: WebSocket,
+    client_id: String,
+    mut notifications: broadcast::Receiver<ClientEvent>,
+) {
+    let (mut sink, mut stream) = socket.split();
+    loop {
+        tokio::select! {
+            event = notifications.recv() => match event {
+                Ok(event) if event.client_id == client_id => {
+                    let message = serde_json::to_string(&event.notification)
+                        .expect("notifications serialize to JSON");
+                    if sink.send(Message::text(message)).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(_) => {}
+                // Closing makes the client resync instead of silently
+                // missing matches
+                Err(RecvError::Lagged(_)) => {
+                    let _ = sink
+                        .send(Message::close_with(1011u16, "missed notifications"))
+                        .await;
+                    return;
+                }
+                Err(RecvError::Closed) => return,
+            },
+            message = stream.next() => match message {
+                Some(Ok(message)) if !message.is_close() => {}
+                _ => return,
+            },
+        }
+    }
+}
+
 pub async fn handle_rejection(
     err: warp::Rejection,
 ) -> Result<impl Reply, std::convert::Infallible> {
     let (code, message) = if let Some(e) = err.find::<Error>() {
         match e {
             Error::ClientNotFound => (StatusCode::NOT_FOUND, "Client Not Found"),
+            Error::Unauthorized => (StatusCode::UNAUTHORIZED, "Unauthorized"),
             Error::InvalidInput(_) => (StatusCode::BAD_REQUEST, "Invalid Input"),
             _ => (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error"),
         }
@@ -1,9 +1,11 @@
 // src/api/routes.rs
 use super::handlers;
+use crate::models::{BlockTweaksQuery, ClientScanQuery, FoundOutputsQuery, TweakStreamQuery};
 use crate::services::{ClientService, ScanService};
 use crate::{
     compute::Compute,
     storage::{ClientStore, UtxoStore},
+    Error,
 };
 use std::sync::Arc;
 use warp::Filter;
@@ -12,21 +14,96 @@ pub fn routes<S: UtxoStore + ClientStore + Send + Sync + 'static, C: Compute + '
     scan_service: Arc<ScanService<S, C>>,
     client_service: Arc<ClientService<S>>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    query_route(scan_service.clone())
-        .or(register_route(client_service))
-        .or(tweak_route(scan_service))
+    query_route(scan_service.clone(), client_service.clone())
+        .or(scan_new_blocks_route(
+            scan_service.clone(),
+            client_service.clone(),
+        ))
+        .or(client_outputs_route(
+            scan_service.clone(),
+            client_service.clone(),
+        ))
+        .or(client_balance_route(
+            scan_service.clone(),
+            client_service.clone(),
+        ))
+        .or(client_ws_route(client_service.clone()))
+        .or(register_route(client_service.clone()))
+        .or(get_client_route(client_service.clone()))
+        .or(update_client_route(client_service.clone()))
+        .or(delete_client_route(client_service))
+        .or(tweak_route(scan_service.clone()))
+        .or(tweak_stream_route(scan_service.clone()))
+        .or(block_tweaks_route(scan_service.clone()))
+        .or(utxo_route(scan_service.clone()))
+        .or(filter_route(scan_service.clone()))
+        .or(filters_route(scan_service))
 }
 
 fn query_route<S: UtxoStore + ClientStore + Send + Sync + 'static, C: Compute + 'static>(
     scan_service: Arc<ScanService<S, C>>,
+    client_service: Arc<ClientService<S>>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path("query")
         .and(warp::post())
         .and(warp::body::json())
+        .and(bearer_token())
+        .and(with_client_service(client_service))
+        .and_then(handlers::authorize_query)
         .and(with_scan_service(scan_service))
         .and_then(handlers::handle_query)
 }
 
+fn scan_new_blocks_route<
+    S: UtxoStore + ClientStore + Send + Sync + 'static,
+    C: Compute + 'static,
+>(
+    scan_service: Arc<ScanService<S, C>>,
+    client_service: Arc<ClientService<S>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::post()
+        .and(authorized(
+            warp::path!("clients" / String / "scan"),
+            client_service,
+        ))
+        .and(warp::query::<ClientScanQuery>())
+        .and(with_scan_service(scan_service))
+        .and_then(handlers::handle_scan_new_blocks)
+}
+
+fn client_outputs_route<
+    S: UtxoStore + ClientStore + Send + Sync + 'static,
+    C: Compute + 'static,
+>(
+    scan_service: Arc<ScanService<S, C>>,
+    client_service: Arc<ClientService<S>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(authorized(
+            warp::path!("clients" / String / "outputs"),
+            client_service,
+        ))
+        .and(warp::query::<FoundOutputsQuery>())
+        .and(with_scan_service(scan_service))
+        .and_then(handlers::handle_client_outputs)
+}
+
+fn client_balance_route<
+    S: UtxoStore + ClientStore + Send + Sync + 'static,
+    C: Compute + 'static,
+>(
+    scan_service: Arc<ScanService<S, C>>,
+    client_service: Arc<ClientService<S>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(authorized(
+            warp::path!("clients" / String / "balance"),
+            client_service,
+        ))
+        .and(with_scan_service(scan_service))
+        .and_then(handlers::handle_client_balance)
+}
+
 fn tweak_route<S: UtxoStore + ClientStore + Send + Sync + 'static, C: Compute + 'static>(
     scan_service: Arc<ScanService<S, C>>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
@@ -37,6 +114,54 @@ fn tweak_route<S: UtxoStore + ClientStore + Send + Sync + 'static, C: Compute +
         .and_then(handlers::handle_tweak)
 }
 
+fn tweak_stream_route<S: UtxoStore + ClientStore + Send + Sync + 'static, C: Compute + 'static>(
+    scan_service: Arc<ScanService<S, C>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("tweaks" / "stream")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("last-event-id"))
+        .and(warp::query::<TweakStreamQuery>())
+        .and(with_scan_service(scan_service))
+        .and_then(handlers::handle_tweak_stream)
+}
+
+fn block_tweaks_route<S: UtxoStore + ClientStore + Send + Sync + 'static, C: Compute + 'static>(
+    scan_service: Arc<ScanService<S, C>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("tweaks" / u64)
+        .and(warp::get())
+        .and(warp::query::<BlockTweaksQuery>())
+        .and(with_scan_service(scan_service))
+        .and_then(handlers::handle_block_tweaks)
+}
+
+fn utxo_route<S: UtxoStore + ClientStore + Send + Sync + 'static, C: Compute + 'static>(
+    scan_service: Arc<ScanService<S, C>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("utxo" / String / u32)
+        .and(warp::get())
+        .and(with_scan_service(scan_service))
+        .and_then(handlers::handle_utxo)
+}
+
+fn filter_route<S: UtxoStore + ClientStore + Send + Sync + 'static, C: Compute + 'static>(
+    scan_service: Arc<ScanService<S, C>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("filters" / u64)
+        .and(warp::get())
+        .and(with_scan_service(scan_service))
+        .and_then(handlers::handle_filter)
+}
+
+fn filters_route<S: UtxoStore + ClientStore + Send + Sync + 'static, C: Compute + 'static>(
+    scan_service: Arc<ScanService<S, C>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("filters" / u64 / u64)
+        .and(warp::get())
+        .and(with_scan_service(scan_service))
+        .and_then(handlers::handle_filters)
+}
+
 fn with_scan_service<S: UtxoStore + ClientStore + Send + Sync + 'static, C: Compute + 'static>(
     scan_service: Arc<ScanService<S, C>>,
 ) -> impl Filter<Extract = (Arc<ScanService<S, C>>,), Error = std::convert::Infallible> + Clone {
@@ -53,8 +178,141 @@ fn register_route<S: ClientStore + Send + Sync + 'static>(
         .and_then(handlers::handle_register)
 }
 
+fn get_client_route<S: ClientStore + Send + Sync + 'static>(
+    client_service: Arc<ClientService<S>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(authorized(
+            warp::path!("clients" / String),
+            client_service.clone(),
+        ))
+        .and(with_client_service(client_service))
+        .and_then(handlers::handle_get_client)
+}
+
+fn update_client_route<S: ClientStore + Send + Sync + 'static>(
+    client_service: Arc<ClientService<S>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::patch()
+        .and(authorized(
+            warp::path!("clients" / String),
+            client_service.clone(),
+        ))
+        .and(warp::body::json())
+        .and(with_client_service(client_service))
+        .and_then(handlers::handle_update_client)
+}
+
+fn delete_client_route<S: ClientStore + Send + Sync + 'static>(
+    client_service: Arc<ClientService<S>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::delete()
+        .and(authorized(
+            warp::path!("clients" / String),
+            client_service.clone(),
+        ))
+        .and(with_client_service(client_service))
+        .and_then(handlers::handle_delete_client)
+}
+
+/// Browsers cannot set headers on a WebSocket, so the token may also be
+/// offered as the `deafen.bearer.<token>` subprotocol, next to the
+/// `deafen` subprotocol the server selects.
+fn client_ws_route<S: ClientStore + Send + Sync + 'static>(
+    client_service: Arc<ClientService<S>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("clients" / String / "ws")
+        .and(bearer_token().or(protocol_token()).unify())
+        .and(with_client_service(client_service.clone()))
+        .and_then(handlers::authorize)
+        .and(warp::ws())
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .and(with_client_service(client_service))
+        .and_then(handlers::handle_client_ws)
+}
+
+/// Passes on the client id extracted by `path` once the request's bearer
+/// token is verified for that client.
+fn authorized<S: ClientStore + Send + Sync + 'static>(
+    path: impl Filter<Extract = (String,), Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    client_service: Arc<ClientService<S>>,
+) -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone {
+    path.and(bearer_token())
+        .and(with_client_service(client_service))
+        .and_then(handlers::authorize)
+}
+
+/// Extracts the token of an `Authorization: Bearer` header.
+fn bearer_token() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization").and_then(
+        |header: Option<String>| async move {
+            header
+                .as_deref()
+                .and_then(parse_bearer)
+                .map(str::to_string)
+                .ok_or_else(|| warp::reject::custom(Error::Unauthorized))
+        },
+    )
+}
+
+/// Extracts the token of a `deafen.bearer.<token>` WebSocket subprotocol.
+fn protocol_token() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("sec-websocket-protocol").and_then(
+        |header: Option<String>| async move {
+            header
+                .as_deref()
+                .and_then(parse_protocol_token)
+                .map(str::to_string)
+                .ok_or_else(|| warp::reject::custom(Error::Unauthorized))
+        },
+    )
+}
+
+/// The auth scheme is case-insensitive, as in RFC 9110.
+fn parse_bearer(header: &str) -> Option<&str> {
+    let (scheme, token) = header.split_once(' ')?;
+    let token = token.trim_start();
+    (scheme.eq_ignore_ascii_case("bearer") && !token.is_empty()).then_some(token)
+}
+
+fn parse_protocol_token(header: &str) -> Option<&str> {
+    header
+        .split(',')
+        .find_map(|protocol| {
+            protocol
+                .trim()
+                .strip_prefix(handlers::WS_TOKEN_PROTOCOL_PREFIX)
+        })
+        .filter(|token| !token.is_empty())
+}
+
 fn with_client_service<S: ClientStore + Send + Sync + 'static>(
     client_service: Arc<ClientService<S>>,
 ) -> impl Filter<Extract = (Arc<ClientService<S>>,), Error = std::convert::Infallible> + Clone {
     warp::any().map(move || client_service.clone())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bearer() {
+        assert_eq!(parse_bearer("Bearer abc"), Some("abc"));
+        assert_eq!(parse_bearer("bearer abc"), Some("abc"));
+        assert_eq!(parse_bearer("BEARER  abc"), Some("abc"));
+        assert_eq!(parse_bearer("Bearer "), None);
+        assert_eq!(parse_bearer("Basic abc"), None);
+        assert_eq!(parse_bearer("Bearerabc"), None);
+    }
+
+    #[test]
+    fn test_parse_protocol_token() {
+        assert_eq!(
+            parse_protocol_token("deafen, deafen.bearer.abc"),
+            Some("abc")
+        );
+        assert_eq!(parse_protocol_token("deafen"), None);
+        assert_eq!(parse_protocol_token("deafen.bearer."), None);
+    }
+}
@@ -1,10 +1,10 @@
 use super::Compute;
-use crate::models::UTXO;
+use crate::models::{MatchedOutput, ScanSecret, UTXO};
 use crate::Result;
 use async_trait::async_trait;
 use rayon::prelude::*;
 use silentpayments::receiving::Receiver;
-use silentpayments::secp256k1::{PublicKey, Secp256k1, SecretKey, XOnlyPublicKey};
+use silentpayments::secp256k1::{PublicKey, Secp256k1, XOnlyPublicKey};
 use silentpayments::utils::receiving::calculate_ecdh_shared_secret;
 
 pub struct LocalCompute {
@@ -25,22 +25,24 @@ impl Compute for LocalCompute {
         &self,
         utxos: &[UTXO],
         receiver: &Receiver,
-        b_scan: &SecretKey,
-    ) -> Result<Vec<UTXO>> {
+        b_scan: &ScanSecret,
+    ) -> Result<Vec<MatchedOutput>> {
+        let b_scan = b_scan.secret_key()?;
         Ok(utxos
             .par_iter()
             .filter_map(|utxo| {
                 let tweak_pubkey = PublicKey::from_slice(&utxo.input_tweak).ok()?;
-                let ecdh_shared_secret = calculate_ecdh_shared_secret(&tweak_pubkey, b_scan);
+                let ecdh_shared_secret = calculate_ecdh_shared_secret(&tweak_pubkey, &b_scan);
                 let pubkey = XOnlyPublicKey::from_slice(&utxo.script_pubkey).ok()?;
                 let scan_result = receiver
                     .scan_transaction(&self.secp, &ecdh_shared_secret, vec![pubkey])
                     .ok()?;
-                if !scan_result.is_empty() {
-                    Some(utxo.clone())
-                } else {
-                    None
-                }
+                // Keyed by the label the output was sent to, if any
+                let label = scan_result.into_keys().next()?;
+                Some(MatchedOutput {
+                    utxo: utxo.clone(),
+                    label: label.map(|label| label.as_string()),
+                })
             })
             .collect())
     }
@@ -49,16 +51,17 @@ impl Compute for LocalCompute {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use silentpayments::secp256k1::{PublicKey, SecretKey};
+    use silentpayments::secp256k1::PublicKey;
     use silentpayments::utils::Network;
     use std::str::FromStr;
 
     #[tokio::test]
     async fn test_local_compute() {
         let compute = LocalCompute::new();
-        let b_scan =
-            SecretKey::from_str("04b2a411635c097759aacd0f005a4c82c8c92862c6fc284b80b8efebc20c3d17")
-                .unwrap();
+        let b_scan = ScanSecret::from_hex(
+            "04b2a411635c097759aacd0f005a4c82c8c92862c6fc284b80b8efebc20c3d17",
+        )
+        .unwrap();
         let receiver = Receiver::new(
             0,
             PublicKey::from_str(
@@ -101,5 +104,6 @@ mod tests {
             .unwrap();
 
         assert_eq!(result.len(), 1);
+        assert_eq!(result[0].utxo, utxos[0]);
     }
 }
@@ -2,11 +2,10 @@ mod local;
 
 pub use local::LocalCompute;
 
-use crate::models::UTXO;
+use crate::models::{MatchedOutput, ScanSecret, UTXO};
 use crate::Result;
 use async_trait::async_trait;
 use silentpayments::receiving::Receiver;
-use silentpayments::secp256k1::SecretKey;
 
 #[async_trait]
 pub trait Compute: Send + Sync {
@@ -14,6 +13,6 @@ pub trait Compute: Send + Sync {
         &self,
         utxos: &[UTXO],
         receiver: &Receiver,
-        b_scan: &SecretKey,
-    ) -> Result<Vec<UTXO>>;
+        b_scan: &ScanSecret,
+    ) -> Result<Vec<MatchedOutput>>;
 }
@@ -5,11 +5,112 @@ use std::path::PathBuf;
 pub struct Config {
     pub db_path: PathBuf,
     pub port: u16,
+    /// File holding the hex encoded key client records are encrypted with,
+    /// `db_path` with a `.key` extension by default. A new key is generated
+    /// there if the file does not exist and no client is registered yet.
+    #[serde(default)]
+    master_key_file: Option<PathBuf>,
+    /// Datadir of a stopped Bitcoin Core node to backfill the index from
+    /// through libbitcoinkernel. `deafend` exits once it reaches the tip.
+    #[serde(default)]
+    pub bitcoin_datadir: Option<PathBuf>,
+    /// One of "mainnet", "testnet", "signet" or "regtest".
+    #[serde(default = "default_network")]
+    pub network: String,
+    /// Bitcoin Core `blocks/` directory to backfill the index from without a
+    /// running node. `deafend` exits once it reaches the tip. Takes
+    /// precedence over `bitcoin_datadir`.
+    #[serde(default)]
+    pub bitcoin_blocks_dir: Option<PathBuf>,
+    /// First block height to index.
+    #[serde(default)]
+    pub start_height: u64,
+    /// Bitcoin Core JSON-RPC endpoint to index blocks from. This is the only
+    /// source that follows new blocks, so the server does not start with the
+    /// datadir or blocks directory set without it.
+    #[serde(default)]
+    pub bitcoin_rpc_url: Option<String>,
+    #[serde(default)]
+    pub bitcoin_rpc_user: Option<String>,
+    #[serde(default)]
+    pub bitcoin_rpc_password: Option<String>,
+    /// Used when no RPC user is set.
+    #[serde(default)]
+    pub bitcoin_rpc_cookie_file: Option<PathBuf>,
+    /// Number of blocks fetched per RPC batch.
+    #[serde(default = "default_rpc_batch_size")]
+    pub rpc_batch_size: usize,
+    #[serde(default = "default_rpc_max_retries")]
+    pub rpc_max_retries: u32,
+    /// Maximum number of blocks a single range request may cover, at
+    /// least 1.
+    #[serde(default = "default_max_range_span")]
+    pub max_range_span: u64,
+    /// Seconds between polls for new blocks over RPC.
+    #[serde(default = "default_poll_interval_secs")]
+    pub poll_interval_secs: u64,
+    /// Attempts at delivering a webhook before it is dropped.
+    #[serde(default = "default_webhook_max_attempts")]
+    pub webhook_max_attempts: u32,
+    /// Seconds before the first webhook retry, doubling with each attempt.
+    #[serde(default = "default_webhook_retry_secs")]
+    pub webhook_retry_secs: u64,
+    /// Seconds between checks of the webhook queue for due deliveries.
+    #[serde(default = "default_webhook_poll_interval_secs")]
+    pub webhook_poll_interval_secs: u64,
+    /// Comma separated hosts webhooks may be delivered to even though they
+    /// are not publicly routable, such as "127.0.0.1,wallet.internal".
+    #[serde(default)]
+    pub webhook_allowed_hosts: Vec<String>,
+}
+
+fn default_network() -> String {
+    "mainnet".to_string()
+}
+
+fn default_max_range_span() -> u64 {
+    10_000
+}
+
+fn default_rpc_batch_size() -> usize {
+    10
+}
+
+fn default_rpc_max_retries() -> u32 {
+    5
+}
+
+fn default_poll_interval_secs() -> u64 {
+    10
+}
+
+fn default_webhook_max_attempts() -> u32 {
+    8
+}
+
+fn default_webhook_retry_secs() -> u64 {
+    30
+}
+
+fn default_webhook_poll_interval_secs() -> u64 {
+    5
 }
 
 impl Config {
+    pub fn master_key_file(&self) -> PathBuf {
+        self.master_key_file
+            .clone()
+            .unwrap_or_else(|| self.db_path.with_extension("key"))
+    }
+
     pub fn from_env() -> Result<Self, envy::Error> {
-        envy::from_env::<Config>()
+        let config = envy::from_env::<Config>()?;
+        if config.max_range_span == 0 {
+            return Err(envy::Error::Custom(
+                "MAX_RANGE_SPAN must be at least 1".to_string(),
+            ));
+        }
+        Ok(config)
     }
 }
 
@@ -22,9 +123,32 @@ mod tests {
     fn test_config_from_env() {
         env::set_var("DB_PATH", "/tmp/test.db");
         env::set_var("PORT", "8080");
+        env::set_var("MASTER_KEY_FILE", "/tmp/master.key");
 
         let config = Config::from_env().unwrap();
         assert_eq!(config.db_path, PathBuf::from("/tmp/test.db"));
         assert_eq!(config.port, 8080);
+        assert_eq!(config.master_key_file(), PathBuf::from("/tmp/master.key"));
+        assert_eq!(config.bitcoin_datadir, None);
+        assert_eq!(config.network, "mainnet");
+        assert_eq!(config.start_height, 0);
+        assert_eq!(config.bitcoin_blocks_dir, None);
+        assert_eq!(config.bitcoin_rpc_url, None);
+        assert_eq!(config.rpc_batch_size, 10);
+        assert_eq!(config.rpc_max_retries, 5);
+        assert_eq!(config.max_range_span, 10_000);
+        assert_eq!(config.poll_interval_secs, 10);
+        assert_eq!(config.webhook_max_attempts, 8);
+        assert_eq!(config.webhook_retry_secs, 30);
+        assert_eq!(config.webhook_poll_interval_secs, 5);
+        assert!(config.webhook_allowed_hosts.is_empty());
+
+        env::remove_var("MASTER_KEY_FILE");
+        let config = Config::from_env().unwrap();
+        assert_eq!(config.master_key_file(), PathBuf::from("/tmp/test.key"));
+
+        env::set_var("MAX_RANGE_SPAN", "0");
+        assert!(Config::from_env().is_err());
+        env::remove_var("MAX_RANGE_SPAN");
     }
 }
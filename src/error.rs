@@ -10,6 +10,8 @@ pub enum Error {
     Compute(String),
     #[error("Client not found")]
     ClientNotFound,
+    #[error("Missing or invalid API token")]
+    Unauthorized,
     #[error("Invalid input: {0}")]
     InvalidInput(String),
     #[error("Crypto error: {0}")]
@@ -18,9 +20,12 @@ pub enum Error {
     SilentPayments(#[from] silentpayments::Error),
     #[error("Secp256k1 error: {0}")]
     Secp256k1(#[from] silentpayments::secp256k1::Error),
+    #[error("Indexer error: {0}")]
+    Indexer(String),
+    #[error("Client records cannot be decrypted, wrong master key")]
+    WrongMasterKey,
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 impl warp::reject::Reject for Error {}
-
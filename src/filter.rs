@@ -0,0 +1,77 @@
+//! BIP158-style compact block filters over taproot output keys.
+//!
+//! A light client that does ECDH locally derives its candidate output keys
+//! per transaction, and can check them against a block's filter before
+//! downloading the block's outputs.
+
+use crate::models::BlockFilter;
+use crate::{Error, Result};
+use bitcoin::bip158::{GcsFilterReader, GcsFilterWriter};
+
+/// Golomb-Rice parameter of BIP158 basic filters.
+const P: u8 = 19;
+/// False positive rate parameter of BIP158 basic filters.
+const M: u64 = 784931;
+
+/// Builds the filter of a block over the x-only output keys of its indexed
+/// taproot outputs.
+pub fn build<'a>(
+    block_hash: [u8; 32],
+    output_keys: impl IntoIterator<Item = &'a [u8; 32]>,
+) -> BlockFilter {
+    let (k0, k1) = siphash_keys(&block_hash);
+    let mut content = Vec::new();
+    let mut writer = GcsFilterWriter::new(&mut content, k0, k1, M, P);
+    for key in output_keys {
+        writer.add_element(key);
+    }
+    writer.finish().expect("writing to a vec does not fail");
+    BlockFilter {
+        block_hash,
+        content,
+    }
+}
+
+/// Returns true if any of `output_keys` may be in the block. False positives
+/// happen at a rate of 1/M.
+pub fn matches_any(filter: &BlockFilter, output_keys: &[[u8; 32]]) -> Result<bool> {
+    let (k0, k1) = siphash_keys(&filter.block_hash);
+    let reader = GcsFilterReader::new(k0, k1, M, P);
+    reader
+        .match_any(
+            &mut filter.content.as_slice(),
+            output_keys.iter().map(|key| &key[..]),
+        )
+        .map_err(|e| Error::InvalidInput(format!("invalid block filter: {}", e)))
+}
+
+/// The SipHash key is the first 16 bytes of the block hash, as in BIP158.
+fn siphash_keys(block_hash: &[u8; 32]) -> (u64, u64) {
+    let k0 = u64::from_le_bytes(block_hash[0..8].try_into().expect("8 bytes"));
+    let k1 = u64::from_le_bytes(block_hash[8..16].try_into().expect("8 bytes"));
+    (k0, k1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_matches_output_keys() {
+        let keys: Vec<[u8; 32]> = (1..=20u8).map(|i| [i; 32]).collect();
+        let filter = build([7; 32], &keys);
+
+        assert!(matches_any(&filter, &[keys[3]]).unwrap());
+        assert!(matches_any(&filter, &[[0xaa; 32], keys[19]]).unwrap());
+        assert!(!matches_any(&filter, &[[0xaa; 32], [0xbb; 32]]).unwrap());
+    }
+
+    #[test]
+    fn test_empty_filter() {
+        let filter = build([7; 32], &[]);
+
+        // Just the element count
+        assert_eq!(filter.content, vec![0]);
+        assert!(!matches_any(&filter, &[[1; 32]]).unwrap());
+    }
+}
@@ -0,0 +1,626 @@
+use super::{BlockSource, SourceBlock};
+use crate::{Error, Result};
+use async_trait::async_trait;
+use bitcoin::block::Header;
+use bitcoin::consensus::deserialize;
+use bitcoin::hashes::{sha256d, Hash};
+use bitcoin::{Amount, Block, BlockHash, Network, ScriptBuf, TxOut, Work};
+use rusty_leveldb::{LdbIterator, Options, DB};
+use silentpayments::secp256k1::PublicKey;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Size of the magic and length prefix of every record in blk and rev files.
+const RECORD_PREFIX_LEN: usize = 8;
+const HEADER_LEN: usize = 80;
+
+/// Prefix of the block entries in the `blocks/index` database.
+const BLOCK_INDEX_PREFIX: u8 = b'b';
+
+// Block status flags from Bitcoin Core's chain.h
+const BLOCK_VALID_MASK: u32 = 0x07;
+const BLOCK_VALID_SCRIPTS: u32 = 5;
+const BLOCK_HAVE_DATA: u32 = 0x08;
+const BLOCK_HAVE_UNDO: u32 = 0x10;
+const BLOCK_FAILED_MASK: u32 = 0x60;
+
+/// Reads blocks straight from the blk*.dat and rev*.dat files of a Bitcoin
+/// Core `blocks/` directory, without a running node.
+///
+/// The directory is expected to be a snapshot of a stopped node: its block
+/// index is read once when opened, and the active chain is the most-work
+/// chain of fully validated blocks in it.
+pub struct BlockFileSource {
+    dir: PathBuf,
+    magic: [u8; 4],
+    xor_key: [u8; 8],
+    chain: Vec<BlockPos>,
+}
+
+/// Where a block and its undo data are stored, from the block index.
+#[derive(Clone, Copy, Debug)]
+struct BlockPos {
+    file: u32,
+    /// Offset of the block's record in its blk file.
+    offset: u64,
+    /// Offset of the undo record in the rev file of the same number.
+    undo_offset: Option<u64>,
+    hash: BlockHash,
+}
+
+/// An entry of the `blocks/index` database.
+struct IndexEntry {
+    height: u64,
+    status: u32,
+    pos: BlockPos,
+    header: Header,
+}
+
+impl BlockFileSource {
+    /// Reads the block index of `dir` and orders it into the active chain.
+    pub fn open(dir: PathBuf, network: Network) -> Result<Self> {
+        // Bitcoin Core 28.0+ obfuscates block files with a key from xor.dat
+        let xor_key = match std::fs::read(dir.join("xor.dat")) {
+            Ok(key) => key
+                .try_into()
+                .map_err(|_| Error::Indexer("xor.dat must hold 8 bytes".to_string()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => [0; 8],
+            Err(e) => return Err(Error::Indexer(format!("failed to read xor.dat: {}", e))),
+        };
+        let entries = read_block_index(&dir.join("index"))?;
+        let chain = active_chain(entries)?;
+        log::info!("found {} blocks on the active chain", chain.len());
+        Ok(Self {
+            dir,
+            magic: network.magic().to_bytes(),
+            xor_key,
+            chain,
+        })
+    }
+
+    fn blk_path(&self, file: u32) -> PathBuf {
+        self.dir.join(format!("blk{:05}.dat", file))
+    }
+
+    fn rev_path(&self, file: u32) -> PathBuf {
+        self.dir.join(format!("rev{:05}.dat", file))
+    }
+
+    /// Reads `len` bytes at `offset` of `file`, undoing the obfuscation.
+    fn read_at(&self, file: &mut File, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0; len];
+        file.seek(SeekFrom::Start(offset))
+            .and_then(|_| file.read_exact(&mut buf))
+            .map_err(|e| Error::Indexer(format!("failed to read block file: {}", e)))?;
+        self.deobfuscate(&mut buf, offset);
+        Ok(buf)
+    }
+
+    fn deobfuscate(&self, buf: &mut [u8], offset: u64) {
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte ^= self.xor_key[(offset as usize + i) % self.xor_key.len()];
+        }
+    }
+
+    /// Reads the length of the record at `offset`, checking its magic.
+    fn read_record_len(&self, file: &mut File, offset: u64, path: &Path) -> Result<usize> {
+        let prefix = self.read_at(file, offset, RECORD_PREFIX_LEN)?;
+        if prefix[..4] != self.magic {
+            return Err(Error::Indexer(format!(
+                "unexpected magic at offset {} of {}",
+                offset,
+                path.display()
+            )));
+        }
+        Ok(u32::from_le_bytes(prefix[4..].try_into().unwrap()) as usize)
+    }
+
+    fn block_pos(&self, height: u64) -> Result<&BlockPos> {
+        self.chain
+            .get(height as usize)
+            .ok_or_else(|| Error::Indexer(format!("no block at height {}", height)))
+    }
+
+    fn read_block(&self, pos: &BlockPos) -> Result<Block> {
+        let path = self.blk_path(pos.file);
+        let mut blk = File::open(&path).map_err(|e| file_error(&path, e))?;
+        let len = self.read_record_len(&mut blk, pos.offset, &path)?;
+        let raw_block = self.read_at(&mut blk, pos.offset + RECORD_PREFIX_LEN as u64, len)?;
+        deserialize(&raw_block).map_err(|e| Error::Indexer(e.to_string()))
+    }
+
+    /// Reads the undo record of `block` at the position the block index
+    /// gives for it.
+    fn read_prevouts(&self, pos: &BlockPos, block: &Block) -> Result<Vec<Vec<TxOut>>> {
+        // Blocks with only a coinbase (including genesis) spend nothing
+        if block.txdata.len() <= 1 {
+            return Ok(vec![]);
+        }
+        let undo_offset = pos
+            .undo_offset
+            .ok_or_else(|| Error::Indexer(format!("no undo data for block {}", pos.hash)))?;
+
+        let path = self.rev_path(pos.file);
+        let mut rev = File::open(&path).map_err(|e| file_error(&path, e))?;
+        let len = self.read_record_len(&mut rev, undo_offset, &path)?;
+        let record = self.read_at(&mut rev, undo_offset + RECORD_PREFIX_LEN as u64, len + 32)?;
+        let (raw, checksum) = record.split_at(len);
+
+        // The checksum commits to the parent, catching a stale index
+        let mut data = block.header.prev_blockhash.to_byte_array().to_vec();
+        data.extend_from_slice(raw);
+        if sha256d::Hash::hash(&data).as_byte_array() != checksum {
+            return Err(Error::Indexer(format!(
+                "undo data checksum mismatch for block {}",
+                pos.hash
+            )));
+        }
+        UndoReader::new(raw).read_block_undo()
+    }
+}
+
+#[async_trait(?Send)]
+impl BlockSource for BlockFileSource {
+    async fn tip_height(&self) -> Result<u64> {
+        (self.chain.len() as u64)
+            .checked_sub(1)
+            .ok_or_else(|| Error::Indexer("no blocks found".to_string()))
+    }
+
+    async fn block_hash(&self, height: u64) -> Result<[u8; 32]> {
+        Ok(self.block_pos(height)?.hash.to_byte_array())
+    }
+
+    async fn block(&self, height: u64) -> Result<SourceBlock> {
+        let pos = self.block_pos(height)?;
+        let block = self.read_block(pos)?;
+        let prevouts = self.read_prevouts(pos, &block)?;
+        Ok(SourceBlock { block, prevouts })
+    }
+}
+
+/// Reads every block entry of Bitcoin Core's block index database.
+fn read_block_index(path: &Path) -> Result<Vec<IndexEntry>> {
+    let options = Options {
+        create_if_missing: false,
+        ..Options::default()
+    };
+    let mut db = DB::open(path, options)
+        .map_err(|e| Error::Indexer(format!("failed to open {}: {}", path.display(), e)))?;
+    let mut iter = db
+        .new_iter()
+        .map_err(|e| Error::Indexer(format!("failed to read block index: {}", e)))?;
+
+    let mut entries = Vec::new();
+    while let Some((key, value)) = LdbIterator::next(&mut iter) {
+        if key.len() != 33 || key[0] != BLOCK_INDEX_PREFIX {
+            continue;
+        }
+        let hash = BlockHash::from_slice(&key[1..]).expect("key holds 32 bytes");
+        entries.push(UndoReader::new(&value).read_index_entry(hash)?);
+    }
+    Ok(entries)
+}
+
+/// Returns the most-work chain of fully validated blocks, indexed by height.
+fn active_chain(mut entries: Vec<IndexEntry>) -> Result<Vec<BlockPos>> {
+    // Parents come before their children once sorted by height
+    entries.sort_by_key(|entry| entry.height);
+    let mut by_hash: HashMap<BlockHash, (usize, Work)> = HashMap::new();
+    let mut best: Option<(Work, usize)> = None;
+    for (index, entry) in entries.iter().enumerate() {
+        let parent_work = by_hash
+            .get(&entry.header.prev_blockhash)
+            .map(|(_, work)| *work);
+        let work = match parent_work {
+            Some(work) => work + entry.header.work(),
+            None => entry.header.work(),
+        };
+        by_hash.insert(entry.pos.hash, (index, work));
+
+        let usable = entry.status & BLOCK_VALID_MASK >= BLOCK_VALID_SCRIPTS
+            && entry.status & BLOCK_HAVE_DATA != 0
+            && entry.status & BLOCK_FAILED_MASK == 0;
+        if usable && best.map_or(true, |(best_work, _)| work > best_work) {
+            best = Some((work, index));
+        }
+    }
+
+    let Some((_, tip)) = best else {
+        return Ok(vec![]);
+    };
+    let mut chain = vec![None; entries[tip].height as usize + 1];
+    let mut next = Some(tip);
+    while let Some(index) = next {
+        let entry = &entries[index];
+        chain[entry.height as usize] = Some(entry.pos);
+        next = by_hash
+            .get(&entry.header.prev_blockhash)
+            .map(|(parent, _)| *parent);
+    }
+    chain
+        .into_iter()
+        .enumerate()
+        .map(|(height, pos)| {
+            pos.ok_or_else(|| {
+                Error::Indexer(format!("block index has no block at height {}", height))
+            })
+        })
+        .collect()
+}
+
+/// Decodes Bitcoin Core's undo serialization.
+struct UndoReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> UndoReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.remaining() < len {
+            return Err(Error::Indexer("truncated undo data".to_string()));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_compact_size(&mut self) -> Result<u64> {
+        Ok(match self.read_u8()? {
+            0xfd => u16::from_le_bytes(self.read_bytes(2)?.try_into().unwrap()) as u64,
+            0xfe => u32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap()) as u64,
+            0xff => u64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap()),
+            n => n as u64,
+        })
+    }
+
+    /// Reads Bitcoin Core's MSB base-128 `VARINT`.
+    fn read_varint(&mut self) -> Result<u64> {
+        let mut n: u64 = 0;
+        loop {
+            let byte = self.read_u8()?;
+            n = n
+                .checked_mul(128)
+                .ok_or_else(|| Error::Indexer("varint overflow".to_string()))?
+                | (byte & 0x7f) as u64;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+            n += 1;
+        }
+    }
+
+    /// Reads a `CDiskBlockIndex`, the value of a block index entry.
+    fn read_index_entry(&mut self, hash: BlockHash) -> Result<IndexEntry> {
+        // Client version
+        self.read_varint()?;
+        let height = self.read_varint()?;
+        let status = self.read_varint()? as u32;
+        // Transaction count
+        self.read_varint()?;
+        let file = match status & (BLOCK_HAVE_DATA | BLOCK_HAVE_UNDO) {
+            0 => 0,
+            _ => self.read_varint()? as u32,
+        };
+        // Positions point past the record prefix
+        let offset = match status & BLOCK_HAVE_DATA {
+            0 => 0,
+            _ => self.read_varint()?.saturating_sub(RECORD_PREFIX_LEN as u64),
+        };
+        let undo_offset = match status & BLOCK_HAVE_UNDO {
+            0 => None,
+            _ => Some(self.read_varint()?.saturating_sub(RECORD_PREFIX_LEN as u64)),
+        };
+        let header: Header =
+            deserialize(self.read_bytes(HEADER_LEN)?).map_err(|e| Error::Indexer(e.to_string()))?;
+        if header.block_hash() != hash {
+            return Err(Error::Indexer(format!(
+                "block index entry {} does not match its header",
+                hash
+            )));
+        }
+        Ok(IndexEntry {
+            height,
+            status,
+            pos: BlockPos {
+                file,
+                offset,
+                undo_offset,
+                hash,
+            },
+            header,
+        })
+    }
+
+    /// Reads a `CBlockUndo`: the spent outputs of each non-coinbase
+    /// transaction.
+    fn read_block_undo(&mut self) -> Result<Vec<Vec<TxOut>>> {
+        (0..self.read_compact_size()?)
+            .map(|_| {
+                (0..self.read_compact_size()?)
+                    .map(|_| self.read_coin())
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Reads a spent coin, skipping its height and coinbase flag.
+    fn read_coin(&mut self) -> Result<TxOut> {
+        let code = self.read_varint()?;
+        if code >> 1 > 0 {
+            // Unused version field kept for compatibility
+            self.read_varint()?;
+        }
+        let value = Amount::from_sat(decompress_amount(self.read_varint()?));
+        let script_pubkey = self.read_script()?;
+        Ok(TxOut {
+            value,
+            script_pubkey,
+        })
+    }
+
+    fn read_script(&mut self) -> Result<ScriptBuf> {
+        let size = self.read_varint()?;
+        let script = match size {
+            0 => {
+                let hash = self.read_bytes(20)?;
+                [&[0x76, 0xa9, 0x14], hash, &[0x88, 0xac]].concat()
+            }
+            1 => {
+                let hash = self.read_bytes(20)?;
+                [&[0xa9, 0x14], hash, &[0x87]].concat()
+            }
+            2 | 3 => {
+                let x = self.read_bytes(32)?;
+                [&[0x21, size as u8], x, &[0xac]].concat()
+            }
+            4 | 5 => {
+                let x = self.read_bytes(32)?;
+                let pubkey = PublicKey::from_slice(&[&[size as u8 - 2], x].concat())
+                    .map_err(|e| Error::Indexer(format!("invalid compressed pubkey: {}", e)))?;
+                [&[0x41], &pubkey.serialize_uncompressed()[..], &[0xac]].concat()
+            }
+            size => self.read_bytes(size as usize - 6)?.to_vec(),
+        };
+        Ok(ScriptBuf::from_bytes(script))
+    }
+}
+
+/// Inverse of Bitcoin Core's `CompressAmount`.
+fn decompress_amount(mut x: u64) -> u64 {
+    if x == 0 {
+        return 0;
+    }
+    x -= 1;
+    let mut e = x % 10;
+    x /= 10;
+    let mut n = if e < 9 {
+        let d = x % 9 + 1;
+        x /= 9;
+        x * 10 + d
+    } else {
+        x + 1
+    };
+    while e > 0 {
+        n *= 10;
+        e -= 1;
+    }
+    n
+}
+
+/// Maps a network name from the config to its `bitcoin` network.
+pub fn network(name: &str) -> Result<Network> {
+    match name {
+        "mainnet" => Ok(Network::Bitcoin),
+        "testnet" => Ok(Network::Testnet),
+        "signet" => Ok(Network::Signet),
+        "regtest" => Ok(Network::Regtest),
+        _ => Err(Error::InvalidInput(format!("unknown network: {}", name))),
+    }
+}
+
+fn file_error(path: &Path, err: std::io::Error) -> Error {
+    Error::Indexer(format!("failed to read {}: {}", path.display(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexer::tests::{assert_indexed, TestChain};
+    use crate::indexer::Indexer;
+    use crate::storage::MemoryStore;
+    use bitcoin::consensus::serialize;
+    use std::sync::Arc;
+
+    const XOR_KEY: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+
+    fn write_varint(out: &mut Vec<u8>, mut n: u64) {
+        let mut bytes = vec![(n & 0x7f) as u8];
+        while n > 0x7f {
+            n = (n >> 7) - 1;
+            bytes.push((n & 0x7f) as u8 | 0x80);
+        }
+        bytes.reverse();
+        out.extend(bytes);
+    }
+
+    fn compress_amount(mut n: u64) -> u64 {
+        if n == 0 {
+            return 0;
+        }
+        let mut e = 0;
+        while n % 10 == 0 && e < 9 {
+            n /= 10;
+            e += 1;
+        }
+        if e < 9 {
+            let d = n % 10;
+            n /= 10;
+            1 + (n * 9 + d - 1) * 10 + e
+        } else {
+            1 + (n - 1) * 10 + 9
+        }
+    }
+
+    /// Serializes the spent outputs of `block` the way Bitcoin Core writes
+    /// them to rev files, as spent at height 100.
+    fn undo_record(magic: [u8; 4], source_block: &SourceBlock) -> Vec<u8> {
+        let mut undo = vec![source_block.prevouts.len() as u8];
+        for spent in &source_block.prevouts {
+            undo.push(spent.len() as u8);
+            for txout in spent {
+                write_varint(&mut undo, 100 << 1);
+                write_varint(&mut undo, 0);
+                write_varint(&mut undo, compress_amount(txout.value.to_sat()));
+                write_varint(&mut undo, txout.script_pubkey.len() as u64 + 6);
+                undo.extend_from_slice(txout.script_pubkey.as_bytes());
+            }
+        }
+        let mut checksum_data = source_block
+            .block
+            .header
+            .prev_blockhash
+            .to_byte_array()
+            .to_vec();
+        checksum_data.extend_from_slice(&undo);
+
+        let mut record = magic.to_vec();
+        record.extend((undo.len() as u32).to_le_bytes());
+        record.extend(&undo);
+        record.extend(sha256d::Hash::hash(&checksum_data).to_byte_array());
+        record
+    }
+
+    fn write_obfuscated(path: PathBuf, mut data: Vec<u8>) {
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte ^= XOR_KEY[i % 8];
+        }
+        std::fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn test_decompress_coin() {
+        // Pairs from Bitcoin Core's compress_tests
+        for (amount, compressed) in [
+            (0, 0x0),
+            (1, 0x1),
+            (1_000_000, 0x7),
+            (100_000_000, 0x9),
+            (5_000_000_000, 0x32),
+            (2_100_000_000_000_000, 0x1406f40),
+        ] {
+            assert_eq!(decompress_amount(compressed), amount);
+            assert_eq!(compress_amount(amount), compressed);
+        }
+
+        // A P2PKH coin from a coinbase at height 5
+        let mut coin = Vec::new();
+        write_varint(&mut coin, (5 << 1) | 1);
+        write_varint(&mut coin, 0);
+        write_varint(&mut coin, 0x32);
+        coin.push(0x00);
+        coin.extend([7; 20]);
+        let txout = UndoReader::new(&coin).read_coin().unwrap();
+        assert_eq!(txout.value, Amount::from_sat(5_000_000_000));
+        assert!(txout.script_pubkey.is_p2pkh());
+        assert_eq!(&txout.script_pubkey.as_bytes()[3..23], &[7; 20]);
+    }
+
+    #[tokio::test]
+    async fn test_sync_from_block_files() {
+        let mut chain = TestChain(vec![]);
+        chain.extend(4, 1);
+        let mut stale = TestChain(chain.0[..2].to_vec());
+        stale.extend(1, 2);
+        let mut headers_only = TestChain(chain.0.clone());
+        headers_only.extend(1, 1);
+
+        // Blocks are written out of order, with a stale block mixed in, and
+        // undo records in yet another order
+        let magic = Network::Regtest.magic().to_bytes();
+        let dir = tempfile::tempdir().unwrap();
+        let mut blk = Vec::new();
+        let mut data_pos = HashMap::new();
+        for source_block in [
+            &chain.0[2],
+            &chain.0[0],
+            &stale.0[2],
+            &chain.0[3],
+            &chain.0[1],
+        ] {
+            let raw_block = serialize(&source_block.block);
+            blk.extend(magic);
+            blk.extend((raw_block.len() as u32).to_le_bytes());
+            data_pos.insert(source_block.block.block_hash(), blk.len() as u64);
+            blk.extend(raw_block);
+        }
+        // Preallocated space
+        blk.extend([0; 64]);
+        let mut rev = Vec::new();
+        let mut undo_pos = HashMap::new();
+        for source_block in chain.0.iter().rev().chain(&stale.0[2..]) {
+            undo_pos.insert(
+                source_block.block.block_hash(),
+                (rev.len() + RECORD_PREFIX_LEN) as u64,
+            );
+            rev.extend(undo_record(magic, source_block));
+        }
+        std::fs::write(dir.path().join("xor.dat"), XOR_KEY).unwrap();
+        write_obfuscated(dir.path().join("blk00000.dat"), blk);
+        write_obfuscated(dir.path().join("rev00000.dat"), rev);
+
+        // The stale block was connected before the reorg, and the last
+        // header's block was never downloaded
+        let connected = BLOCK_VALID_SCRIPTS | BLOCK_HAVE_DATA | BLOCK_HAVE_UNDO;
+        let mut index = DB::open(dir.path().join("index"), Options::default()).unwrap();
+        let entries = chain
+            .0
+            .iter()
+            .enumerate()
+            .map(|(height, b)| (height, connected, b))
+            .chain([(2, connected, &stale.0[2]), (4, 2, &headers_only.0[4])]);
+        for (height, status, source_block) in entries {
+            let hash = source_block.block.block_hash();
+            let mut key = vec![BLOCK_INDEX_PREFIX];
+            key.extend(hash.to_byte_array());
+            let mut value = Vec::new();
+            write_varint(&mut value, 280000);
+            write_varint(&mut value, height as u64);
+            write_varint(&mut value, status as u64);
+            write_varint(&mut value, source_block.block.txdata.len() as u64);
+            if status & BLOCK_HAVE_DATA != 0 {
+                write_varint(&mut value, 0);
+                write_varint(&mut value, data_pos[&hash]);
+                write_varint(&mut value, undo_pos[&hash]);
+            }
+            value.extend(serialize(&source_block.block.header));
+            index.put(&key, &value).unwrap();
+        }
+        index.flush().unwrap();
+        drop(index);
+
+        let source = BlockFileSource::open(dir.path().to_path_buf(), Network::Regtest).unwrap();
+        let store = Arc::new(MemoryStore::new());
+        let indexer = Indexer::new(store.clone(), 0);
+        assert_eq!(indexer.sync(&source).await.unwrap(), 3);
+        assert_indexed(&store, &chain).await;
+
+        let read = source.block(2).await.unwrap();
+        assert_eq!(read.block, chain.0[2].block);
+        assert_eq!(read.prevouts, chain.0[2].prevouts);
+    }
+}
@@ -0,0 +1,165 @@
+use super::{BlockSource, IndexEvent, Indexer, SourceBlock};
+use crate::kernel::create_context;
+use crate::storage::UtxoStore;
+use crate::{Error, Result};
+use async_trait::async_trait;
+use bitcoin::consensus::deserialize;
+use bitcoin::{Amount, Block, ScriptBuf, TxOut};
+use libbitcoinkernel_sys::{
+    BlockIndex, BlockManagerOptions, ChainType, ChainstateLoadOptions, ChainstateManager,
+    ChainstateManagerOptions, KernelError,
+};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::runtime::Handle;
+use tokio::sync::broadcast;
+
+/// Builds the tweak index from a local Bitcoin Core datadir through
+/// libbitcoinkernel.
+///
+/// The datadir must not be in use by a running node, so this only backfills
+/// the index up to the tip it has; new blocks are followed with `RpcSource`.
+pub struct KernelIndexer<S: UtxoStore> {
+    store: Arc<S>,
+    datadir: PathBuf,
+    chain_type: ChainType,
+    start_height: u64,
+    events: Option<broadcast::Sender<IndexEvent>>,
+}
+
+impl<S: UtxoStore> KernelIndexer<S> {
+    pub fn new(store: Arc<S>, datadir: PathBuf, chain_type: ChainType, start_height: u64) -> Self {
+        Self {
+            store,
+            datadir,
+            chain_type,
+            start_height,
+            events: None,
+        }
+    }
+
+    /// See `Indexer::with_events`.
+    pub fn with_events(mut self, events: broadcast::Sender<IndexEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Loads the chainstate and indexes every block up to the current tip,
+    /// returning the tip height.
+    ///
+    /// This blocks the calling thread; store writes are driven on `runtime`.
+    pub fn run(self, runtime: &Handle) -> Result<u64> {
+        let context = create_context(self.chain_type);
+        let blocks_dir = self.datadir.join("blocks");
+        let chainman = ChainstateManager::new(
+            ChainstateManagerOptions::new(&context, &self.datadir.to_string_lossy())
+                .map_err(kernel_error)?,
+            BlockManagerOptions::new(&context, &blocks_dir.to_string_lossy())
+                .map_err(kernel_error)?,
+            &context,
+        )
+        .map_err(kernel_error)?;
+        chainman
+            .load_chainstate(ChainstateLoadOptions::new())
+            .map_err(kernel_error)?;
+        chainman.import_blocks().map_err(kernel_error)?;
+
+        let source = KernelSource {
+            chainman: &chainman,
+        };
+        let mut indexer = Indexer::new(self.store, self.start_height);
+        if let Some(events) = self.events {
+            indexer = indexer.with_events(events);
+        }
+        let tip = runtime.block_on(indexer.sync(&source))?;
+        log::info!("indexed blocks up to height {}", tip);
+
+        Ok(tip)
+    }
+}
+
+/// Reads blocks and their undo data from a loaded chainstate.
+struct KernelSource<'a> {
+    chainman: &'a ChainstateManager<'a>,
+}
+
+impl KernelSource<'_> {
+    fn block_index(&self, height: u64) -> Result<BlockIndex> {
+        self.chainman
+            .get_block_index_by_height(height as i32)
+            .map_err(kernel_error)
+    }
+
+    fn read_block(&self, height: u64) -> Result<(BlockIndex, Block)> {
+        let block_index = self.block_index(height)?;
+        let raw_block: Vec<u8> = self
+            .chainman
+            .read_block_data(&block_index)
+            .map_err(kernel_error)?
+            .into();
+        let block = deserialize(&raw_block).map_err(|e| Error::Indexer(e.to_string()))?;
+        Ok((block_index, block))
+    }
+
+    /// Reads the outputs spent by each non-coinbase transaction of `block`
+    /// from its undo data.
+    fn read_prevouts(&self, block_index: &BlockIndex, block: &Block) -> Result<Vec<Vec<TxOut>>> {
+        // Blocks with only a coinbase (including genesis) have no undo data
+        if block.txdata.len() <= 1 {
+            return Ok(vec![]);
+        }
+
+        let undo = self
+            .chainman
+            .read_undo_data(block_index)
+            .map_err(kernel_error)?;
+        (0..undo.n_tx_undo as u64)
+            .map(|tx_index| {
+                (0..undo.get_transaction_undo_size(tx_index))
+                    .map(|input_index| {
+                        let prevout = undo
+                            .get_prevout_by_index(tx_index, input_index)
+                            .map_err(kernel_error)?;
+                        Ok(TxOut {
+                            value: Amount::from_sat(prevout.get_value() as u64),
+                            script_pubkey: ScriptBuf::from_bytes(prevout.get_script_pubkey().get()),
+                        })
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+#[async_trait(?Send)]
+impl BlockSource for KernelSource<'_> {
+    async fn tip_height(&self) -> Result<u64> {
+        Ok(self.chainman.get_block_index_tip().height() as u64)
+    }
+
+    async fn block_hash(&self, height: u64) -> Result<[u8; 32]> {
+        // The hash is kept in the block index, so the block is not read
+        Ok(self.block_index(height)?.block_hash().hash)
+    }
+
+    async fn block(&self, height: u64) -> Result<SourceBlock> {
+        let (block_index, block) = self.read_block(height)?;
+        let prevouts = self.read_prevouts(&block_index, &block)?;
+        Ok(SourceBlock { block, prevouts })
+    }
+}
+
+/// Maps a network name from the config to the kernel chain type.
+pub fn chain_type(network: &str) -> Result<ChainType> {
+    match network {
+        "mainnet" => Ok(ChainType::MAINNET),
+        "testnet" => Ok(ChainType::TESTNET),
+        "signet" => Ok(ChainType::SIGNET),
+        "regtest" => Ok(ChainType::REGTEST),
+        _ => Err(Error::InvalidInput(format!("unknown network: {}", network))),
+    }
+}
+
+fn kernel_error(err: KernelError) -> Error {
+    Error::Indexer(format!("{:?}", err))
+}
@@ -0,0 +1,499 @@
+mod files;
+mod kernel;
+mod rpc;
+
+pub use files::{network, BlockFileSource};
+pub use kernel::{chain_type, KernelIndexer};
+pub use rpc::{RpcAuth, RpcConfig, RpcSource};
+
+use crate::filter;
+use crate::models::{BlockInfo, IndexedBlock, SpentInfo, UTXO};
+use crate::storage::UtxoStore;
+use crate::tweak::input_tweak;
+use crate::{Error, Result};
+use async_trait::async_trait;
+use bitcoin::hashes::Hash;
+use bitcoin::{Block, Transaction, TxOut};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// A block together with the outputs spent by each of its non-coinbase
+/// transactions, in block order.
+#[derive(Clone, Debug)]
+pub struct SourceBlock {
+    pub block: Block,
+    pub prevouts: Vec<Vec<TxOut>>,
+}
+
+/// The active chain of a node the indexer reads blocks from.
+///
+/// Sources are not required to be `Send`, so indexers run on a dedicated
+/// thread that drives them with `Handle::block_on`.
+#[async_trait(?Send)]
+pub trait BlockSource {
+    async fn tip_height(&self) -> Result<u64>;
+    async fn block_hash(&self, height: u64) -> Result<[u8; 32]>;
+    async fn block(&self, height: u64) -> Result<SourceBlock>;
+}
+
+/// A change to the index, published once it is committed to the store.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexEvent {
+    BlockIndexed {
+        height: u64,
+        block_hash: [u8; 32],
+    },
+    /// Everything above `height` was rolled back.
+    RolledBack {
+        height: u64,
+    },
+}
+
+/// Keeps a `UtxoStore` in sync with the active chain of a `BlockSource`.
+pub struct Indexer<S: UtxoStore> {
+    store: Arc<S>,
+    start_height: u64,
+    events: Option<broadcast::Sender<IndexEvent>>,
+}
+
+impl<S: UtxoStore> Indexer<S> {
+    pub fn new(store: Arc<S>, start_height: u64) -> Self {
+        Self {
+            store,
+            start_height,
+            events: None,
+        }
+    }
+
+    /// Publishes an `IndexEvent` to `events` for every indexed or rolled
+    /// back block.
+    pub fn with_events(mut self, events: broadcast::Sender<IndexEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Indexes blocks up to the tip of `source`, resuming after the last
+    /// fully indexed block and first rolling back indexed blocks that are no
+    /// longer on its active chain. Returns the tip height.
+    pub async fn sync<B: BlockSource>(&self, source: &B) -> Result<u64> {
+        let mut height = match self.store.index_state().await? {
+            Some(state) => self.rewind(source, state.height).await?,
+            None => self.start_height,
+        };
+
+        let tip = source.tip_height().await?;
+        while height <= tip {
+            let SourceBlock { block, prevouts } = source.block(height).await?;
+            let parent = match height.checked_sub(1) {
+                Some(parent_height) => self.store.get_block_info(parent_height).await?,
+                None => None,
+            };
+            // The source switched branches since the parent was indexed
+            if parent
+                .is_some_and(|parent| block.header.prev_blockhash.to_byte_array() != parent.hash)
+            {
+                height = self.rewind(source, height - 1).await?;
+                continue;
+            }
+            let count = index_block(self.store.as_ref(), height, &block, &prevouts).await?;
+            log::debug!("indexed {} outputs at height {}", count, height);
+            self.publish(IndexEvent::BlockIndexed {
+                height,
+                block_hash: block.block_hash().to_byte_array(),
+            });
+            height += 1;
+        }
+
+        Ok(tip)
+    }
+
+    /// Keeps following `source`, indexing new blocks every `poll_interval`.
+    pub async fn follow<B: BlockSource>(&self, source: &B, poll_interval: Duration) {
+        loop {
+            match self.sync(source).await {
+                Ok(tip) => log::debug!("index synced to height {}", tip),
+                Err(e) => log::error!("failed to sync index: {}", e),
+            }
+            tokio::time::sleep(poll_interval).await;
+        }
+    }
+
+    /// Rolls back the indexed blocks at or below `height` that are not on
+    /// the active chain of `source`, and returns the next height to index.
+    async fn rewind<B: BlockSource>(&self, source: &B, height: u64) -> Result<u64> {
+        let fork_point = match self.find_fork_point(source, height).await? {
+            Some(fork_point) => fork_point,
+            None => self.start_height.checked_sub(1).ok_or_else(|| {
+                Error::Indexer("indexed chain does not match the block source".to_string())
+            })?,
+        };
+        if fork_point < height {
+            log::warn!(
+                "reorg detected, rolling back from height {} to {}",
+                height,
+                fork_point
+            );
+            self.store.rollback_to(fork_point).await?;
+            self.publish(IndexEvent::RolledBack { height: fork_point });
+        }
+        Ok(fork_point + 1)
+    }
+
+    fn publish(&self, event: IndexEvent) {
+        // Sending only fails when nobody is subscribed
+        if let Some(events) = &self.events {
+            let _ = events.send(event);
+        }
+    }
+
+    /// Returns the highest indexed block at or below `height` that is still
+    /// on the active chain of `source`.
+    async fn find_fork_point<B: BlockSource>(
+        &self,
+        source: &B,
+        mut height: u64,
+    ) -> Result<Option<u64>> {
+        let source_tip = source.tip_height().await?;
+        loop {
+            let Some(indexed) = self.store.get_block_info(height).await? else {
+                return Ok(None);
+            };
+            if height <= source_tip && source.block_hash(height).await? == indexed.hash {
+                return Ok(Some(height));
+            }
+            if height <= self.start_height {
+                return Ok(None);
+            }
+            height -= 1;
+        }
+    }
+}
+
+/// Writes the taproot outputs of every silent payment eligible transaction in
+/// `block` to `store`, along with the taproot outputs it spends, the block's
+/// compact filter and its hashes, in a single commit.
+///
+/// `prevouts` holds the outputs spent by each non-coinbase transaction, in
+/// block order. Returns the number of outputs written.
+pub async fn index_block<S: UtxoStore + ?Sized>(
+    store: &S,
+    height: u64,
+    block: &Block,
+    prevouts: &[Vec<TxOut>],
+) -> Result<usize> {
+    let indexed = indexed_block(height, block, prevouts)?;
+    let count = indexed.utxos.len();
+    store.add_block(indexed).await?;
+    Ok(count)
+}
+
+/// Computes everything indexed for `block`, see `index_block`.
+pub fn indexed_block(height: u64, block: &Block, prevouts: &[Vec<TxOut>]) -> Result<IndexedBlock> {
+    if block.txdata.len().saturating_sub(1) != prevouts.len() {
+        return Err(Error::Indexer(format!(
+            "block {} has {} transactions but prevouts for {}",
+            height,
+            block.txdata.len(),
+            prevouts.len()
+        )));
+    }
+
+    let mut utxos = Vec::new();
+    let mut spends = Vec::new();
+    for (tx, spent) in block.txdata.iter().skip(1).zip(prevouts) {
+        utxos.extend(silent_payment_utxos(tx, spent)?);
+        // Only taproot outputs can be silent payments, so only their spends
+        // are tracked
+        let spend = SpentInfo {
+            height,
+            txid: tx.compute_txid().to_byte_array(),
+        };
+        for (txin, prevout) in tx.input.iter().zip(spent) {
+            if prevout.script_pubkey.is_p2tr() {
+                let outpoint = txin.previous_output;
+                spends.push((outpoint.txid.to_byte_array(), outpoint.vout, spend));
+            }
+        }
+    }
+
+    let hash = block.block_hash().to_byte_array();
+    let filter = filter::build(hash, utxos.iter().map(|utxo| &utxo.script_pubkey));
+    Ok(IndexedBlock {
+        height,
+        info: BlockInfo {
+            hash,
+            prev_hash: block.header.prev_blockhash.to_byte_array(),
+        },
+        utxos,
+        spends,
+        filter,
+    })
+}
+
+/// Returns the taproot outputs of `tx` together with its input tweak, or
+/// nothing if the transaction is not eligible for silent payments.
+pub fn silent_payment_utxos(tx: &Transaction, prevouts: &[TxOut]) -> Result<Vec<UTXO>> {
+    let Some(input_tweak) = input_tweak(tx, prevouts)? else {
+        return Ok(vec![]);
+    };
+    let txid = tx.compute_txid().to_byte_array();
+
+    Ok(tx
+        .output
+        .iter()
+        .enumerate()
+        .filter(|(_, output)| output.script_pubkey.is_p2tr())
+        .map(|(vout, output)| UTXO {
+            txid,
+            vout: vout as u32,
+            amount: output.value.to_sat(),
+            script_pubkey: output.script_pubkey.as_bytes()[2..34]
+                .try_into()
+                .expect("P2TR output key is 32 bytes"),
+            input_tweak,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStore;
+    use bitcoin::block::{self, Header};
+    use bitcoin::hashes::hash160;
+    use bitcoin::{
+        absolute, transaction, Amount, BlockHash, CompactTarget, OutPoint, ScriptBuf, Sequence,
+        TxIn, TxMerkleNode, Txid, Witness,
+    };
+
+    const INPUT_PUBKEY: &str = "03bbc63f12745d3b9e9d24c6cd7a1efebad0a7f469232fbecf31fba7b4f7ddeda8";
+    const OUTPUT_KEY: &str = "596b20b0f02f9b085a801ee276ce9f21470c0d30b633372617c564a2a2fda171";
+
+    fn p2wpkh_spend() -> (Transaction, TxOut) {
+        let pubkey = hex::decode(INPUT_PUBKEY).unwrap();
+        let mut prevout_script = vec![0x00, 0x14];
+        prevout_script.extend_from_slice(&hash160::Hash::hash(&pubkey).to_byte_array());
+        let mut output_script = vec![0x51, 0x20];
+        output_script.extend_from_slice(&hex::decode(OUTPUT_KEY).unwrap());
+
+        let tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::from_byte_array([1; 32]), 0),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::from_slice(&[vec![0x30; 71], pubkey]),
+            }],
+            output: vec![
+                TxOut {
+                    value: Amount::from_sat(5000),
+                    script_pubkey: ScriptBuf::from_bytes(prevout_script.clone()),
+                },
+                TxOut {
+                    value: Amount::from_sat(10000),
+                    script_pubkey: ScriptBuf::from_bytes(output_script),
+                },
+            ],
+        };
+        let prevout = TxOut {
+            value: Amount::from_sat(20000),
+            script_pubkey: ScriptBuf::from_bytes(prevout_script),
+        };
+        (tx, prevout)
+    }
+
+    #[tokio::test]
+    async fn test_index_block() {
+        let (tx, prevout) = p2wpkh_spend();
+        let coinbase = Transaction {
+            input: vec![],
+            output: vec![],
+            ..tx.clone()
+        };
+        let block = Block {
+            header: bitcoin::constants::genesis_block(bitcoin::Network::Regtest).header,
+            txdata: vec![coinbase, tx.clone()],
+        };
+        let store = MemoryStore::new();
+
+        let count = index_block(&store, 7, &block, &[vec![prevout.clone()]])
+            .await
+            .unwrap();
+
+        // Only the taproot output is indexed
+        assert_eq!(count, 1);
+        let utxos = store.query_utxos(7).await.unwrap();
+        assert_eq!(utxos.len(), 1);
+        assert_eq!(utxos[0].txid, tx.compute_txid().to_byte_array());
+        assert_eq!(utxos[0].vout, 1);
+        assert_eq!(utxos[0].amount, 10000);
+        assert_eq!(hex::encode(utxos[0].script_pubkey), OUTPUT_KEY);
+        assert_eq!(
+            Some(utxos[0].input_tweak),
+            input_tweak(&tx, &[prevout]).unwrap()
+        );
+        let block_filter = store.get_filter(7).await.unwrap().unwrap();
+        assert_eq!(block_filter.block_hash, block.block_hash().to_byte_array());
+        assert!(filter::matches_any(&block_filter, &[utxos[0].script_pubkey]).unwrap());
+
+        // Spending the taproot output in a later block is recorded
+        let spender = Transaction {
+            input: vec![TxIn {
+                previous_output: OutPoint::new(tx.compute_txid(), 1),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::from_slice(&[vec![0x01; 64]]),
+            }],
+            ..tx.clone()
+        };
+        let block = Block {
+            header: block.header,
+            txdata: vec![block.txdata[0].clone(), spender.clone()],
+        };
+        index_block(&store, 8, &block, &[vec![tx.output[1].clone()]])
+            .await
+            .unwrap();
+        assert_eq!(
+            store.get_spend(utxos[0].txid, 1).await.unwrap(),
+            Some(SpentInfo {
+                height: 8,
+                txid: spender.compute_txid().to_byte_array(),
+            })
+        );
+        assert_eq!(store.get_spend(utxos[0].txid, 0).await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_no_taproot_outputs() {
+        let (mut tx, prevout) = p2wpkh_spend();
+        tx.output.truncate(1);
+        assert!(silent_payment_utxos(&tx, &[prevout]).unwrap().is_empty());
+    }
+
+    /// A chain where every block spends a P2WPKH output to a taproot output.
+    pub(super) struct TestChain(pub(super) Vec<SourceBlock>);
+
+    impl TestChain {
+        pub(super) fn extend(&mut self, count: usize, branch: u32) {
+            for _ in 0..count {
+                let height = self.0.len() as u32;
+                let prev_blockhash = self
+                    .0
+                    .last()
+                    .map_or(BlockHash::all_zeros(), |b| b.block.block_hash());
+                let (mut tx, prevout) = p2wpkh_spend();
+                // Make every transaction unique
+                tx.lock_time = absolute::LockTime::from_consensus(branch * 1000 + height);
+                let coinbase = Transaction {
+                    input: vec![],
+                    output: vec![],
+                    ..tx.clone()
+                };
+                let header = Header {
+                    version: block::Version::ONE,
+                    prev_blockhash,
+                    merkle_root: TxMerkleNode::all_zeros(),
+                    time: branch,
+                    bits: CompactTarget::from_consensus(0x207fffff),
+                    nonce: height,
+                };
+                self.0.push(SourceBlock {
+                    block: Block {
+                        header,
+                        txdata: vec![coinbase, tx],
+                    },
+                    prevouts: vec![vec![prevout]],
+                });
+            }
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl BlockSource for TestChain {
+        async fn tip_height(&self) -> Result<u64> {
+            Ok(self.0.len() as u64 - 1)
+        }
+
+        async fn block_hash(&self, height: u64) -> Result<[u8; 32]> {
+            Ok(self.block(height).await?.block.block_hash().to_byte_array())
+        }
+
+        async fn block(&self, height: u64) -> Result<SourceBlock> {
+            self.0
+                .get(height as usize)
+                .cloned()
+                .ok_or_else(|| Error::Indexer(format!("no block at height {}", height)))
+        }
+    }
+
+    pub(super) async fn assert_indexed(store: &MemoryStore, chain: &TestChain) {
+        for (height, source_block) in chain.0.iter().enumerate() {
+            let height = height as u64;
+            let info = store.get_block_info(height).await.unwrap().unwrap();
+            assert_eq!(info.hash, source_block.block.block_hash().to_byte_array());
+            let utxos = store.query_utxos(height).await.unwrap();
+            assert_eq!(utxos.len(), 1);
+            assert_eq!(
+                utxos[0].txid,
+                source_block.block.txdata[1].compute_txid().to_byte_array()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sync_handles_reorg() {
+        let store = Arc::new(MemoryStore::new());
+        let (events, mut published) = broadcast::channel(64);
+        let indexer = Indexer::new(store.clone(), 0).with_events(events);
+        let mut chain = TestChain(vec![]);
+        chain.extend(4, 1);
+        assert_eq!(indexer.sync(&chain).await.unwrap(), 3);
+        assert_indexed(&store, &chain).await;
+
+        // Replace blocks 2 and 3 with a longer branch
+        chain.0.truncate(2);
+        chain.extend(3, 2);
+        assert_eq!(indexer.sync(&chain).await.unwrap(), 4);
+        assert_indexed(&store, &chain).await;
+        assert_eq!(store.tip().await.unwrap().unwrap().0, 4);
+        let events: Vec<_> = std::iter::from_fn(|| published.try_recv().ok()).collect();
+        assert_eq!(events.len(), 8);
+        assert_eq!(events[4], IndexEvent::RolledBack { height: 1 });
+        assert_eq!(
+            events[7],
+            IndexEvent::BlockIndexed {
+                height: 4,
+                block_hash: chain.0[4].block.block_hash().to_byte_array(),
+            }
+        );
+
+        // A shorter branch rolls back the blocks above it
+        chain.0.truncate(1);
+        chain.extend(1, 3);
+        assert_eq!(indexer.sync(&chain).await.unwrap(), 1);
+        assert_indexed(&store, &chain).await;
+        assert_eq!(store.get_block_info(2).await.unwrap(), None);
+        assert!(store.query_utxos(2).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sync_resumes_from_index_state() {
+        let store = Arc::new(MemoryStore::new());
+        let mut chain = TestChain(vec![]);
+        chain.extend(3, 1);
+        Indexer::new(store.clone(), 0).sync(&chain).await.unwrap();
+
+        // A restarted indexer only indexes the new blocks, so no block ends
+        // up with its outputs written twice
+        chain.extend(2, 1);
+        let indexer = Indexer::new(store.clone(), 0);
+        assert_eq!(indexer.sync(&chain).await.unwrap(), 4);
+        assert_indexed(&store, &chain).await;
+        assert_eq!(
+            store.index_state().await.unwrap().unwrap().block_hash,
+            chain.0[4].block.block_hash().to_byte_array()
+        );
+    }
+}
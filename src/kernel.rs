@@ -1,9 +1,7 @@
-use env_logger::Builder;
 use libbitcoinkernel_sys::{
     ChainType, Context, ContextBuilder, KernelError, KernelNotificationInterfaceCallbackHolder,
     Log, Logger,
 };
-use log::LevelFilter;
 
 pub struct MainLog {}
 
@@ -15,9 +13,9 @@ impl Log for MainLog {
     }
 }
 
+/// Forwards the kernel's log messages to the `log` crate. The global logger
+/// itself is set up by the binary.
 pub fn setup_logging() -> Result<Logger<MainLog>, KernelError> {
-    let mut builder = Builder::from_default_env();
-    builder.filter(None, LevelFilter::Info).init();
     Logger::new(MainLog {})
 }
 
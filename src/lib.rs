@@ -2,8 +2,12 @@ pub mod api;
 pub mod compute;
 pub mod config;
 pub mod error;
+pub mod filter;
+pub mod indexer;
+pub mod kernel;
 pub mod models;
 pub mod services;
 pub mod storage;
+pub mod tweak;
 
 pub use error::{Error, Result};
@@ -3,26 +3,124 @@ use deafen::{
     api,
     compute::LocalCompute,
     config::Config,
-    services::{ClientService, ScanService, UtxoService},
-    storage::MdbxDatabase,
+    indexer::{self, BlockFileSource, Indexer, KernelIndexer, RpcAuth, RpcConfig, RpcSource},
+    kernel,
+    services::{ClientService, ScanService, ScanWorker, UtxoService, WebhookWorker},
+    storage::{MasterKey, MdbxDatabase},
 };
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use warp::Filter;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
     let config = Config::from_env()?;
 
-    let db = Arc::new(MdbxDatabase::new(config.db_path)?);
+    let db = MdbxDatabase::open(config.db_path.clone(), &config.master_key_file())?;
+
+    // `deafend rotate-master-key <file>` re-encrypts client records under the
+    // key in <file> and exits without starting the server
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.as_slice() {
+        [] => {}
+        [command, new_master_key_file] if command == "rotate-master-key" => {
+            let new_master_key_file = PathBuf::from(new_master_key_file);
+            let count = db.rotate_master_key(MasterKey::load_or_create(&new_master_key_file)?)?;
+            log::warn!(
+                "re-encrypted {} client records, set MASTER_KEY_FILE to {} before restarting",
+                count,
+                new_master_key_file.display()
+            );
+            return Ok(());
+        }
+        _ => return Err("usage: deafend [rotate-master-key <new master key file>]".into()),
+    }
+    let db = Arc::new(db);
+
+    // The kernel and block file sources read the datadir of a stopped node,
+    // so they only backfill the index up to its tip and nothing would tell
+    // the scanner, pushes, webhooks or tweak stream about new blocks. They
+    // run once and exit; the server follows new blocks over RPC.
+    if config.bitcoin_rpc_url.is_none() {
+        let runtime = tokio::runtime::Handle::current();
+        let backfilled = if let Some(blocks_dir) = config.bitcoin_blocks_dir.clone() {
+            let network = indexer::network(&config.network)?;
+            let indexer = Indexer::new(db.clone(), config.start_height);
+            let task = tokio::task::spawn_blocking(move || {
+                let source = BlockFileSource::open(blocks_dir, network)?;
+                runtime.block_on(indexer.sync(&source))
+            });
+            Some(task.await??)
+        } else if let Some(datadir) = config.bitcoin_datadir.clone() {
+            let indexer = KernelIndexer::new(
+                db.clone(),
+                datadir,
+                indexer::chain_type(&config.network)?,
+                config.start_height,
+            );
+            let task = tokio::task::spawn_blocking(move || {
+                let _logger = kernel::setup_logging().expect("failed to set up kernel logging");
+                indexer.run(&runtime)
+            });
+            Some(task.await??)
+        } else {
+            None
+        };
+        if let Some(tip) = backfilled {
+            log::info!(
+                "backfilled the index up to height {}, set BITCOIN_RPC_URL to follow new blocks",
+                tip
+            );
+            return Ok(());
+        }
+    }
+
     let compute = Arc::new(LocalCompute::new());
+    let utxo_service = Arc::new(UtxoService::new(db.clone(), config.max_range_span));
+    let index_events = utxo_service.index_events();
+    // Subscribed before the indexer starts, so the scan worker sees every block
+    let scan_events = utxo_service.subscribe();
+
+    if let Some(url) = config.bitcoin_rpc_url.clone() {
+        let auth = match (&config.bitcoin_rpc_user, &config.bitcoin_rpc_cookie_file) {
+            (Some(user), _) => RpcAuth::UserPass {
+                user: user.clone(),
+                password: config.bitcoin_rpc_password.clone().unwrap_or_default(),
+            },
+            (None, Some(path)) => RpcAuth::CookieFile(path.clone()),
+            (None, None) => RpcAuth::None,
+        };
+        let source = RpcSource::new(RpcConfig {
+            url,
+            auth,
+            batch_size: config.rpc_batch_size,
+            max_retries: config.rpc_max_retries,
+        });
+        let indexer =
+            Indexer::new(db.clone(), config.start_height).with_events(index_events.clone());
+        let poll_interval = Duration::from_secs(config.poll_interval_secs);
+        let runtime = tokio::runtime::Handle::current();
+        std::thread::spawn(move || runtime.block_on(indexer.follow(&source, poll_interval)));
+    }
 
-    let utxo_service = Arc::new(UtxoService::new(db.clone()));
     let client_service = Arc::new(ClientService::new(db.clone()));
     let scan_service = Arc::new(ScanService::new(
         utxo_service.clone(),
         client_service.clone(),
-        compute,
+        compute.clone(),
     ));
+    let scan_worker = ScanWorker::new(utxo_service.clone(), client_service.clone(), compute);
+    tokio::spawn(async move { scan_worker.run(scan_events).await });
+    let webhook_worker = WebhookWorker::new(
+        client_service.clone(),
+        config.webhook_max_attempts,
+        Duration::from_secs(config.webhook_retry_secs),
+    )
+    .with_allowed_hosts(config.webhook_allowed_hosts);
+    let webhook_poll_interval = Duration::from_secs(config.webhook_poll_interval_secs);
+    tokio::spawn(async move { webhook_worker.run(webhook_poll_interval).await });
 
     let routes = api::routes(scan_service, client_service)
         .with(warp::cors().allow_any_origin())
@@ -1,11 +1,141 @@
+use crate::Result;
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, Bytes};
 use silentpayments::receiving::Receiver;
+use silentpayments::secp256k1::SecretKey;
+use std::fmt;
+use std::sync::Arc;
+use zeroize::Zeroize;
 
+/// A registered client. The secrets are shared behind an `Arc`, so copies of
+/// a record never duplicate the key bytes.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ClientData {
     pub receiver: Receiver,
-    pub b_scan: [u8; 32],
+    pub b_scan: Arc<ScanSecret>,
+    pub info: ClientInfo,
+    /// SHA256 of the client's API token. The token itself is never stored.
+    pub token_hash: [u8; 32],
+    /// Highest block scanned for the client so far.
+    pub last_scanned_height: Option<u64>,
+    /// Key the client's webhook bodies are signed with, if it has a webhook.
+    pub webhook_secret: Option<Arc<WebhookSecret>>,
+}
+
+/// The public details a client registered with, kept so the receiver can be
+/// rebuilt and described without the scan key.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ClientInfo {
+    pub version: u32,
+    pub scan_pubkey: String,
+    pub spend_pubkey: String,
+    pub change_label: String,
+    pub network: String,
+    /// Height of the first block that can hold the client's outputs.
+    pub birthday_height: u64,
+    /// URL the client's notifications are POSTed to.
+    pub webhook_url: Option<String>,
+    /// Labels added to the receiver besides the change label.
+    pub labels: Vec<ClientLabel>,
+}
+
+/// A label added to a client's receiver.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClientLabel {
+    /// The label's integer, if it was registered as one.
+    pub m: Option<u32>,
+    /// Hex encoded label tweak, as reported on the outputs sent to it.
+    pub tweak: String,
+}
+
+/// A label to add to a client: either its integer `m`, from which the tweak
+/// is derived with the scan key, or the hex encoded tweak itself.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum LabelRequest {
+    Index(u32),
+    Tweak(String),
+}
+
+/// A client label and its address.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LabelAddress {
+    pub m: Option<u32>,
+    pub tweak: String,
+    pub address: String,
+}
+
+/// A client's scan secret key. It is zeroed on drop, redacted from debug
+/// output and cannot be copied.
+#[derive(PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ScanSecret([u8; 32]);
+
+impl ScanSecret {
+    /// Parses a hex encoded secret key.
+    pub fn from_hex(hex_key: &str) -> Result<Self> {
+        let mut bytes = [0u8; 32];
+        let decoded = hex::decode_to_slice(hex_key, &mut bytes);
+        let secret = ScanSecret(bytes);
+        bytes.zeroize();
+        decoded.map_err(|_| {
+            crate::Error::InvalidInput("scan key must be 32 hex encoded bytes".to_string())
+        })?;
+        secret.secret_key()?;
+        Ok(secret)
+    }
+
+    pub fn secret_key(&self) -> Result<SecretKey> {
+        Ok(SecretKey::from_slice(&self.0)?)
+    }
+}
+
+impl From<[u8; 32]> for ScanSecret {
+    fn from(bytes: [u8; 32]) -> Self {
+        ScanSecret(bytes)
+    }
+}
+
+impl fmt::Debug for ScanSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ScanSecret(<redacted>)")
+    }
+}
+
+impl Drop for ScanSecret {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+/// Key webhook bodies are signed with. Like `ScanSecret`, it is zeroed on
+/// drop, redacted from debug output and cannot be copied.
+#[derive(PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct WebhookSecret([u8; 32]);
+
+impl WebhookSecret {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl From<[u8; 32]> for WebhookSecret {
+    fn from(bytes: [u8; 32]) -> Self {
+        WebhookSecret(bytes)
+    }
+}
+
+impl fmt::Debug for WebhookSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("WebhookSecret(<redacted>)")
+    }
+}
+
+impl Drop for WebhookSecret {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -16,12 +146,70 @@ pub struct RegistrationRequest {
     pub change_label: String,
     pub network: String,
     pub b_scan: String,
+    /// Blocks below this height are never scanned for the client.
+    #[serde(default)]
+    pub birthday_height: u64,
+    /// http(s) URL to POST the client's notifications to.
+    #[serde(default)]
+    pub webhook_url: Option<String>,
+    #[serde(default)]
+    pub labels: Vec<LabelRequest>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RegistrationResponse {
     pub client_id: String,
     pub receiving_address: String,
+    /// Bearer token for the client-scoped routes. It is only returned here.
+    pub api_token: String,
+    /// The registered labels, in the order requested.
+    pub labels: Vec<LabelAddress>,
+    /// Hex encoded key of the signatures on webhook requests, issued when a
+    /// webhook URL was registered. It is only returned here.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook_secret: Option<String>,
+}
+
+/// Changes to a registered client. Fields left out are kept.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ClientUpdateRequest {
+    #[serde(default)]
+    pub change_label: Option<String>,
+    /// Replaces the client's labels.
+    #[serde(default)]
+    pub labels: Option<Vec<LabelRequest>>,
+}
+
+/// A registered client's public details, which never include its scan key.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClientInfoResponse {
+    pub client_id: String,
+    pub receiving_address: String,
+    pub network: String,
+    /// Outputs sent to the change label report this as their label. It has
+    /// no address, since change addresses are never handed out.
+    pub change_label: String,
+    pub labels: Vec<LabelAddress>,
+    pub birthday_height: u64,
+    pub last_scanned_height: Option<u64>,
+    pub webhook_url: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ClientScanQuery {
+    /// Only return outputs that are unspent as of the indexed tip.
+    #[serde(default)]
+    pub unspent_only: bool,
+}
+
+/// Outputs found for a client in the blocks after its last scanned height.
+/// Scans cover at most the maximum range span, so clients repeat the request
+/// until `last_scanned_height` reaches `tip_height`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClientScanResponse {
+    pub last_scanned_height: Option<u64>,
+    pub tip_height: Option<u64>,
+    pub utxos: Vec<ClientOutput>,
 }
 
 #[serde_as]
@@ -36,16 +224,278 @@ pub struct UTXO {
     pub input_tweak: [u8; 33],
 }
 
+/// An output that belongs to a client, with the hex encoded label it was
+/// sent to, if any.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MatchedOutput {
+    #[serde(flatten)]
+    pub utxo: UTXO,
+    pub label: Option<String>,
+}
+
+/// An output found for a registered client, by the background scanner or
+/// one of its scan requests.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FoundOutput {
+    pub height: u64,
+    pub utxo: UTXO,
+    pub label: Option<String>,
+}
+
+/// A message pushed to a client over its WebSocket.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientNotification {
+    /// Outputs found for the client in a newly indexed block.
+    Found {
+        height: u64,
+        outputs: Vec<FoundOutput>,
+    },
+    /// Outputs found earlier whose blocks above `height` were rolled back.
+    /// This covers every found output of the client in those blocks,
+    /// including ones only returned by its own scan requests and never
+    /// pushed as `Found`.
+    Invalidated {
+        height: u64,
+        outputs: Vec<FoundOutput>,
+    },
+}
+
+/// A notification addressed to one client.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientEvent {
+    pub client_id: String,
+    pub notification: ClientNotification,
+}
+
+/// The body POSTed to a client's webhook.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebhookPayload {
+    /// Unique per delivery and kept across retries, so receivers can drop
+    /// duplicates.
+    pub delivery_id: String,
+    pub client_id: String,
+    pub notification: ClientNotification,
+}
+
+/// A signed webhook request waiting in the delivery queue.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WebhookDelivery {
+    pub id: String,
+    pub client_id: String,
+    pub url: String,
+    /// The JSON encoded `WebhookPayload`.
+    pub body: String,
+    /// Hex encoded HMAC-SHA256 of `body` under the client's webhook secret.
+    pub signature: String,
+    /// Failed attempts so far.
+    pub attempts: u32,
+    /// Unix time, in seconds, of the next attempt.
+    pub next_attempt: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FoundOutputsQuery {
+    /// The `next` cursor of the previous page.
+    pub after: Option<String>,
+    pub limit: Option<usize>,
+}
+
+/// A found output as served over the API, with its spend.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClientOutput {
+    pub height: u64,
+    pub label: Option<String>,
+    #[serde(flatten)]
+    pub output: UtxoWithSpend,
+}
+
+/// One page of a client's found outputs, ordered by height.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClientOutputsPage {
+    pub outputs: Vec<ClientOutput>,
+    /// Cursor to pass as `after` for the next page, unset on the last one.
+    pub next: Option<String>,
+}
+
+/// Sums of a client's found outputs, in satoshis, as of the indexed tip.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ClientBalance {
+    pub tip_height: Option<u64>,
+    pub unspent: u64,
+    pub unspent_outputs: usize,
+    pub spent: u64,
+    pub spent_outputs: usize,
+}
+
+/// The block height and transaction that spent an indexed output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpentInfo {
+    pub height: u64,
+    pub txid: [u8; 32],
+}
+
+/// An indexed output and its spend, if it was spent as of the indexed tip.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UtxoWithSpend {
+    #[serde(flatten)]
+    pub utxo: UTXO,
+    pub spent: Option<SpentInfo>,
+}
+
+/// An indexed output looked up by outpoint, with the height of its block.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct IndexedOutput {
+    pub height: u64,
+    #[serde(flatten)]
+    pub output: UtxoWithSpend,
+}
+
+/// Hash and parent hash of an indexed block, in internal byte order.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockInfo {
+    pub hash: [u8; 32],
+    pub prev_hash: [u8; 32],
+}
+
+/// The last block whose index data was fully written, in internal byte
+/// order. Indexers resume from here after a restart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexState {
+    pub height: u64,
+    pub block_hash: [u8; 32],
+}
+
+/// Everything indexed for one block, written to a store at once.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IndexedBlock {
+    pub height: u64,
+    pub info: BlockInfo,
+    pub utxos: Vec<UTXO>,
+    /// Spent taproot outputs as `(txid, vout, spend)`. Stores drop the ones
+    /// that were never indexed.
+    pub spends: Vec<([u8; 32], u32, SpentInfo)>,
+    pub filter: BlockFilter,
+}
+
+/// A block's compact filter over the output keys of its indexed taproot
+/// outputs. The filter is keyed by the block hash, in internal byte order.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockFilter {
+    pub block_hash: [u8; 32],
+    pub content: Vec<u8>,
+}
+
+/// A block filter as served over the API, with the block hash in RPC byte
+/// order and the filter hex encoded.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlockFilterResponse {
+    pub height: u64,
+    pub block_hash: String,
+    pub filter: String,
+}
+
+/// Body of `POST /query`. The matches found are also kept as the client's
+/// found outputs.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ScanRequest {
     pub block_height: u64,
     pub client_id: String,
+    /// Only return outputs that are unspent as of the indexed tip.
+    #[serde(default)]
+    pub unspent_only: bool,
+    /// Return each output's spend next to it.
+    #[serde(default)]
+    pub with_spent_info: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TweakRequest {
     pub start_height: u64,
     pub end_height: u64,
+    /// Only return outputs that are unspent as of the indexed tip.
+    #[serde(default)]
+    pub unspent_only: bool,
+    /// Return each output's spend next to it.
+    #[serde(default)]
+    pub with_spent_info: bool,
+    /// Leave out the tweaks of transactions whose outputs are all spent.
+    /// The outputs are then wrapped in a `TweakResponse` instead of being
+    /// returned as a bare array.
+    #[serde(default)]
+    pub cut_through: bool,
+}
+
+/// Reply to a tweak range query with cut-through.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TweakResponse<T> {
+    pub utxos: Vec<T>,
+    /// Number of input tweaks left out by cut-through.
+    pub cut_through_omitted: usize,
+}
+
+/// Encoding of the tweaks returned by the compact tweak endpoint.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TweakFormat {
+    /// JSON with hex encoded tweaks.
+    #[default]
+    Hex,
+    /// Concatenated 33-byte compressed points.
+    Binary,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlockTweaksQuery {
+    #[serde(default)]
+    pub format: TweakFormat,
+    #[serde(default)]
+    pub cut_through: bool,
+}
+
+/// One deduplicated input tweak per eligible transaction of a block.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlockTweaks {
+    pub height: u64,
+    pub tweaks: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TweakStreamQuery {
+    #[serde(default)]
+    pub cut_through: bool,
+}
+
+/// An event of the tweak stream. Its SSE id is `height:block_hash`, so a
+/// client that reconnects with it as `Last-Event-ID` resumes at the next
+/// block, or is told of the rollback if that block was replaced meanwhile.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TweakStreamEvent {
+    /// The tweaks of a newly indexed block, as in `BlockTweaks`. The hash is
+    /// in RPC byte order.
+    Block {
+        height: u64,
+        block_hash: String,
+        tweaks: Vec<String>,
+    },
+    /// Blocks above `height` were rolled back, and the blocks replacing them
+    /// follow. `block_hash` is that of the block now at `height`.
+    RolledBack { height: u64, block_hash: String },
+}
+
+impl TweakStreamEvent {
+    /// The SSE id of the event.
+    pub fn id(&self) -> String {
+        match self {
+            TweakStreamEvent::Block {
+                height, block_hash, ..
+            }
+            | TweakStreamEvent::RolledBack { height, block_hash } => {
+                format!("{}:{}", height, block_hash)
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -72,6 +522,33 @@ mod tests {
         assert_eq!(utxo, deserialized);
     }
 
+    #[test]
+    fn test_utxo_with_spend_reads_as_utxo() {
+        let utxo = UTXO {
+            txid: [0; 32],
+            vout: 1,
+            amount: 100000,
+            script_pubkey: [1; 32],
+            input_tweak: [2; 33],
+        };
+        let with_spend = UtxoWithSpend {
+            utxo: utxo.clone(),
+            spent: Some(SpentInfo {
+                height: 10,
+                txid: [3; 32],
+            }),
+        };
+
+        let serialized = serde_json::to_string(&with_spend).unwrap();
+
+        // Clients that only know about UTXO can still read the response
+        assert_eq!(serde_json::from_str::<UTXO>(&serialized).unwrap(), utxo);
+        assert_eq!(
+            serde_json::from_str::<UtxoWithSpend>(&serialized).unwrap(),
+            with_spend
+        );
+    }
+
     #[test]
     fn test_client_data_serialization() {
         let receiver = Receiver::new(
@@ -94,13 +571,38 @@ mod tests {
 
         let client_data = ClientData {
             receiver,
-            b_scan: [0; 32],
+            b_scan: Arc::new(ScanSecret::from([1; 32])),
+            info: ClientInfo {
+                version: 0,
+                scan_pubkey: "03bbc63f12745d3b9e9d24c6cd7a1efebad0a7f469232fbecf31fba7b4f7ddeda8"
+                    .to_string(),
+                spend_pubkey: "0381eb9a9a9ec739d527c1631b31b421566f5c2a47b4ab5b1f6a686dfb68eab716"
+                    .to_string(),
+                change_label: "3e9fce73d4e77a4809908e3c3a2e54ee147b9312dc5044a193d1fc85de46e3c1"
+                    .to_string(),
+                network: "mainnet".to_string(),
+                birthday_height: 0,
+                webhook_url: None,
+                labels: vec![],
+            },
+            token_hash: [2; 32],
+            last_scanned_height: None,
+            webhook_secret: None,
         };
 
         let serialized = serde_json::to_string(&client_data).unwrap();
         let deserialized: ClientData = serde_json::from_str(&serialized).unwrap();
 
         assert_eq!(client_data.b_scan, deserialized.b_scan);
+        // Copies of a record share its scan key
+        assert!(Arc::ptr_eq(
+            &client_data.clone().b_scan,
+            &client_data.b_scan
+        ));
+
+        // The scan key never shows up in debug output
+        assert!(format!("{:?}", client_data).contains("ScanSecret(<redacted>)"));
+        assert!(!format!("{:?}", client_data).contains("[1, 1"));
         assert_eq!(
             client_data.receiver.get_receiving_address(),
             deserialized.receiver.get_receiving_address()
@@ -1,67 +1,428 @@
-use crate::models::{ClientData, RegistrationRequest, RegistrationResponse};
+use crate::models::{
+    ClientData, ClientEvent, ClientInfo, ClientInfoResponse, ClientLabel, ClientUpdateRequest,
+    FoundOutput, IndexState, LabelAddress, LabelRequest, MatchedOutput, RegistrationRequest,
+    RegistrationResponse, ScanSecret, WebhookDelivery, WebhookPayload, WebhookSecret,
+};
 use crate::storage::ClientStore;
-use crate::Result;
+use crate::{Error, Result};
+use bitcoin::hashes::{hmac, sha256, Hash, HashEngine};
+use rand::RngCore;
 use silentpayments::receiving::{Label, Receiver};
-use silentpayments::secp256k1::{PublicKey, SecretKey};
+use silentpayments::secp256k1::{PublicKey, Scalar, Secp256k1};
 use silentpayments::utils::Network;
 use std::str::FromStr;
 use std::sync::Arc;
+use tokio::sync::broadcast;
 use uuid::Uuid;
+use zeroize::Zeroize;
+
+/// Number of client notifications a slow subscriber can fall behind by.
+const NOTIFICATION_CAPACITY: usize = 256;
+/// Most labels a client can have besides its change label. Every label
+/// adds to the cost of scanning an output for the client.
+const MAX_LABELS: usize = 100;
 
 pub struct ClientService<S: ClientStore + Send + Sync> {
     store: Arc<S>,
+    notifications: broadcast::Sender<ClientEvent>,
 }
 
 impl<S: ClientStore + Send + Sync> ClientService<S> {
     pub fn new(store: Arc<S>) -> Self {
-        Self { store }
+        let (notifications, _) = broadcast::channel(NOTIFICATION_CAPACITY);
+        Self {
+            store,
+            notifications,
+        }
+    }
+
+    /// Receives the notifications sent to every client from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<ClientEvent> {
+        self.notifications.subscribe()
     }
 
-    pub async fn register_client(&self, req: RegistrationRequest) -> Result<RegistrationResponse> {
-        let scan_pubkey = PublicKey::from_str(&req.scan_pubkey)?;
-        let spend_pubkey = PublicKey::from_str(&req.spend_pubkey)?;
-        let change_label = Label::try_from(req.change_label)?;
-        let network: Network = match req.network.as_str() {
-            "mainnet" => Network::Mainnet,
-            _ => Network::Regtest,
+    /// Sends `event` to the subscribers and, if the client registered a
+    /// webhook, queues a signed delivery of it.
+    pub async fn notify(&self, event: ClientEvent) -> Result<()> {
+        let client_data = match self.store.get_client_data(&event.client_id).await {
+            Ok(client_data) => Some(client_data),
+            // Deleted since the event was raised
+            Err(Error::ClientNotFound) => None,
+            Err(e) => return Err(e),
         };
-        let b_scan = SecretKey::from_str(&req.b_scan)?;
+        let webhook = client_data.as_ref().and_then(|client_data| {
+            Some((
+                client_data.info.webhook_url.as_ref()?,
+                client_data.webhook_secret.as_ref()?,
+            ))
+        });
+        if let Some((url, secret)) = webhook {
+            let id = Uuid::new_v4().to_string();
+            let body = serde_json::to_string(&WebhookPayload {
+                delivery_id: id.clone(),
+                client_id: event.client_id.clone(),
+                notification: event.notification.clone(),
+            })
+            .expect("notifications serialize to JSON");
+            self.store
+                .queue_webhook(WebhookDelivery {
+                    id,
+                    client_id: event.client_id.clone(),
+                    url: url.clone(),
+                    signature: webhook_signature(secret, &body),
+                    body,
+                    attempts: 0,
+                    next_attempt: 0,
+                })
+                .await?;
+        }
+        // Sending only fails when nobody is subscribed
+        let _ = self.notifications.send(event);
+        Ok(())
+    }
+
+    pub async fn pending_webhooks(&self) -> Result<Vec<WebhookDelivery>> {
+        self.store.pending_webhooks().await
+    }
+
+    /// Puts a delivery back in the queue, e.g. after a failed attempt.
+    pub async fn requeue_webhook(&self, delivery: WebhookDelivery) -> Result<()> {
+        self.store.queue_webhook(delivery).await
+    }
+
+    pub async fn remove_webhook(&self, id: &str) -> Result<()> {
+        self.store.remove_webhook(id).await
+    }
 
-        let receiver = Receiver::new(
-            req.version,
-            scan_pubkey,
-            spend_pubkey,
-            change_label,
-            network,
-        )?;
+    pub async fn register_client(
+        &self,
+        mut req: RegistrationRequest,
+    ) -> Result<RegistrationResponse> {
+        let b_scan = ScanSecret::from_hex(&req.b_scan);
+        req.b_scan.zeroize();
+        let b_scan = Arc::new(b_scan?);
+        if let Some(url) = &req.webhook_url {
+            validate_webhook_url(url)?;
+        }
+        let labels = resolve_labels(&b_scan, &req.labels)?;
+
+        let info = ClientInfo {
+            version: req.version,
+            scan_pubkey: req.scan_pubkey,
+            spend_pubkey: req.spend_pubkey,
+            change_label: req.change_label,
+            network: req.network,
+            birthday_height: req.birthday_height,
+            webhook_url: req.webhook_url,
+            labels,
+        };
+        let receiver = build_receiver(&info)?;
+        let labels = label_addresses(&info)?;
 
         let client_id = Uuid::new_v4().to_string();
+        let mut token = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut token);
+        let api_token = hex::encode(token);
+        token.zeroize();
+        let webhook_secret = info.webhook_url.as_ref().map(|_| {
+            let mut secret = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut secret);
+            let webhook_secret = Arc::new(WebhookSecret::from(secret));
+            secret.zeroize();
+            webhook_secret
+        });
 
+        let receiving_address = receiver.get_receiving_address();
         let client_data = ClientData {
-            receiver: receiver.clone(),
-            b_scan: b_scan.secret_bytes(),
+            receiver,
+            b_scan,
+            info,
+            token_hash: token_hash(&api_token),
+            last_scanned_height: None,
+            webhook_secret: webhook_secret.clone(),
         };
 
         self.store
             .store_client_data(&client_id, client_data)
             .await?;
 
-        let receiving_address = receiver.get_receiving_address();
-
         Ok(RegistrationResponse {
             client_id,
             receiving_address,
+            api_token,
+            labels,
+            webhook_secret: webhook_secret.map(|secret| hex::encode(secret.as_bytes())),
         })
     }
 
+    /// Checks `api_token` against the one issued to the client. Unknown
+    /// clients fail the same way as wrong tokens.
+    pub async fn authenticate(&self, client_id: &str, api_token: &str) -> Result<()> {
+        let client_data = match self.store.get_client_data(client_id).await {
+            Ok(client_data) => client_data,
+            Err(Error::ClientNotFound) => return Err(Error::Unauthorized),
+            Err(e) => return Err(e),
+        };
+        let expected = client_data.token_hash;
+        let actual = token_hash(api_token);
+        // Compare without short-circuiting
+        let diff = expected
+            .iter()
+            .zip(actual.iter())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+        if diff != 0 {
+            return Err(Error::Unauthorized);
+        }
+        Ok(())
+    }
+
     pub async fn get_client_data(&self, client_id: &str) -> Result<ClientData> {
         self.store.get_client_data(client_id).await
     }
+
+    pub async fn get_client_info(&self, client_id: &str) -> Result<ClientInfoResponse> {
+        let client_data = self.store.get_client_data(client_id).await?;
+        client_info(client_id, &client_data)
+    }
+
+    /// Applies `req` to a registered client, rebuilding its receiver. The
+    /// scan key is kept.
+    pub async fn update_client(
+        &self,
+        client_id: &str,
+        req: ClientUpdateRequest,
+    ) -> Result<ClientInfoResponse> {
+        let mut client_data = self.store.get_client_data(client_id).await?;
+        if let Some(change_label) = req.change_label {
+            client_data.info.change_label = change_label;
+        }
+        if let Some(labels) = req.labels {
+            client_data.info.labels = resolve_labels(&client_data.b_scan, &labels)?;
+        }
+        client_data.receiver = build_receiver(&client_data.info)?;
+
+        let response = client_info(client_id, &client_data)?;
+        self.store.update_client(client_id, client_data).await?;
+        Ok(response)
+    }
+
+    /// Records that the client's blocks up to `height` have been scanned.
+    pub async fn set_last_scanned_height(&self, client_id: &str, height: u64) -> Result<()> {
+        let mut client_data = self.store.get_client_data(client_id).await?;
+        client_data.last_scanned_height = Some(height);
+        self.store.update_client(client_id, client_data).await
+    }
+
+    pub async fn delete_client(&self, client_id: &str) -> Result<()> {
+        self.store.delete_client(client_id).await
+    }
+
+    pub async fn list_clients(&self) -> Result<Vec<String>> {
+        self.store.list_clients().await
+    }
+
+    pub async fn add_found_outputs(
+        &self,
+        client_id: &str,
+        height: u64,
+        outputs: Vec<MatchedOutput>,
+    ) -> Result<()> {
+        self.store
+            .add_found_outputs(client_id, height, outputs)
+            .await
+    }
+
+    pub async fn get_found_outputs(&self, client_id: &str) -> Result<Vec<FoundOutput>> {
+        self.store.get_found_outputs(client_id).await
+    }
+
+    pub async fn found_outputs_page(
+        &self,
+        client_id: &str,
+        after: Option<(u64, [u8; 32], u32)>,
+        limit: usize,
+    ) -> Result<Vec<FoundOutput>> {
+        self.store.found_outputs_page(client_id, after, limit).await
+    }
+
+    /// Returns the last block scanned by the background scanner.
+    pub async fn scan_state(&self) -> Result<Option<IndexState>> {
+        self.store.scan_state().await
+    }
+
+    pub async fn set_scan_state(&self, state: Option<IndexState>) -> Result<()> {
+        self.store.set_scan_state(state).await
+    }
+
+    pub async fn add_failed_scan(&self, client_id: &str, height: u64) -> Result<()> {
+        self.store.add_failed_scan(client_id, height).await
+    }
+
+    pub async fn failed_scans(&self) -> Result<Vec<(u64, String)>> {
+        self.store.failed_scans().await
+    }
+
+    pub async fn remove_failed_scan(&self, client_id: &str, height: u64) -> Result<()> {
+        self.store.remove_failed_scan(client_id, height).await
+    }
+
+    /// Forgets the outputs found, the blocks scanned and the failed scans
+    /// above `height`, after those blocks were rolled back from the index.
+    /// Returns the forgotten outputs with their client id.
+    pub async fn rollback(&self, height: u64) -> Result<Vec<(String, FoundOutput)>> {
+        let removed = self.store.rollback_found_outputs(height).await?;
+        self.store.rollback_failed_scans(height).await?;
+        for client_id in self.store.list_clients().await? {
+            let mut client_data = match self.store.get_client_data(&client_id).await {
+                Ok(client_data) => client_data,
+                // Deleted since it was listed
+                Err(Error::ClientNotFound) => continue,
+                Err(e) => return Err(e),
+            };
+            if client_data
+                .last_scanned_height
+                .is_some_and(|scanned| scanned > height)
+            {
+                client_data.last_scanned_height = Some(height);
+                self.store.update_client(&client_id, client_data).await?;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+fn build_receiver(info: &ClientInfo) -> Result<Receiver> {
+    let scan_pubkey = PublicKey::from_str(&info.scan_pubkey)?;
+    let spend_pubkey = PublicKey::from_str(&info.spend_pubkey)?;
+    let change_label = Label::try_from(info.change_label.clone())?;
+    let network: Network = match info.network.as_str() {
+        "mainnet" => Network::Mainnet,
+        _ => Network::Regtest,
+    };
+
+    let mut receiver = Receiver::new(
+        info.version,
+        scan_pubkey,
+        spend_pubkey,
+        change_label,
+        network,
+    )?;
+    for label in &info.labels {
+        receiver.add_label(Label::try_from(label.tweak.clone())?)?;
+    }
+    Ok(receiver)
+}
+
+/// Derives or parses the tweak of each requested label. Label 0 is the
+/// change label, so it cannot be requested.
+fn resolve_labels(b_scan: &ScanSecret, labels: &[LabelRequest]) -> Result<Vec<ClientLabel>> {
+    if labels.len() > MAX_LABELS {
+        return Err(Error::InvalidInput(format!(
+            "{} labels exceed the maximum of {}",
+            labels.len(),
+            MAX_LABELS
+        )));
+    }
+    labels
+        .iter()
+        .map(|label| match label {
+            LabelRequest::Index(0) => Err(Error::InvalidInput(
+                "label 0 is reserved for change".to_string(),
+            )),
+            LabelRequest::Index(m) => Ok(ClientLabel {
+                m: Some(*m),
+                tweak: label_tweak(b_scan, *m)?,
+            }),
+            // Parsed and re-encoded, so it reads the same as the labels
+            // reported by scans
+            LabelRequest::Tweak(tweak) => Ok(ClientLabel {
+                m: None,
+                tweak: Label::try_from(tweak.clone())?.as_string(),
+            }),
+        })
+        .collect()
+}
+
+/// The BIP352 tweak of label `m`: hash_BIP0352/Label(b_scan || m).
+fn label_tweak(b_scan: &ScanSecret, m: u32) -> Result<String> {
+    let tag = sha256::Hash::hash(b"BIP0352/Label");
+    let mut secret = b_scan.secret_key()?.secret_bytes();
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag.as_byte_array());
+    engine.input(tag.as_byte_array());
+    engine.input(&secret);
+    engine.input(&m.to_be_bytes());
+    secret.zeroize();
+    Ok(hex::encode(
+        sha256::Hash::from_engine(engine).to_byte_array(),
+    ))
+}
+
+/// The address of each of the client's labels, whose spend key is the
+/// client's tweaked by the label: B_m = B_spend + label·G.
+fn label_addresses(info: &ClientInfo) -> Result<Vec<LabelAddress>> {
+    let secp = Secp256k1::verification_only();
+    let spend_pubkey = PublicKey::from_str(&info.spend_pubkey)?;
+    info.labels
+        .iter()
+        .map(|label| {
+            let mut tweak = [0u8; 32];
+            hex::decode_to_slice(&label.tweak, &mut tweak)
+                .map_err(|_| Error::InvalidInput(format!("invalid label {}", label.tweak)))?;
+            let tweak = Scalar::from_be_bytes(tweak)
+                .map_err(|_| Error::InvalidInput(format!("invalid label {}", label.tweak)))?;
+            let labeled = ClientInfo {
+                spend_pubkey: spend_pubkey.add_exp_tweak(&secp, &tweak)?.to_string(),
+                labels: Vec::new(),
+                ..info.clone()
+            };
+            Ok(LabelAddress {
+                m: label.m,
+                tweak: label.tweak.clone(),
+                address: build_receiver(&labeled)?.get_receiving_address(),
+            })
+        })
+        .collect()
+}
+
+fn token_hash(api_token: &str) -> [u8; 32] {
+    sha256::Hash::hash(api_token.as_bytes()).to_byte_array()
+}
+
+/// Accepts absolute http(s) URLs only. Where they may be delivered to is
+/// checked by `WebhookWorker` on every attempt, as the host can change.
+fn validate_webhook_url(url: &str) -> Result<()> {
+    match reqwest::Url::parse(url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => Ok(()),
+        _ => Err(Error::InvalidInput(format!("invalid webhook URL: {}", url))),
+    }
+}
+
+/// Hex encoded HMAC-SHA256 of a webhook body, sent in the
+/// `X-Deafen-Signature` header.
+pub fn webhook_signature(secret: &WebhookSecret, body: &str) -> String {
+    let mut engine = hmac::HmacEngine::<sha256::Hash>::new(secret.as_bytes());
+    engine.input(body.as_bytes());
+    hex::encode(hmac::Hmac::<sha256::Hash>::from_engine(engine).to_byte_array())
+}
+
+fn client_info(client_id: &str, client_data: &ClientData) -> Result<ClientInfoResponse> {
+    Ok(ClientInfoResponse {
+        client_id: client_id.to_string(),
+        receiving_address: client_data.receiver.get_receiving_address(),
+        network: client_data.info.network.clone(),
+        change_label: client_data.info.change_label.clone(),
+        labels: label_addresses(&client_data.info)?,
+        birthday_height: client_data.info.birthday_height,
+        last_scanned_height: client_data.last_scanned_height,
+        webhook_url: client_data.info.webhook_url.clone(),
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::services::tests::registration_request;
     use crate::storage::MemoryStore;
 
     #[tokio::test]
@@ -70,15 +431,14 @@ mod tests {
         let service = ClientService::new(store);
 
         let request = RegistrationRequest {
-            version: 0,
-            scan_pubkey: "03bbc63f12745d3b9e9d24c6cd7a1efebad0a7f469232fbecf31fba7b4f7ddeda8"
-                .to_string(),
-            spend_pubkey: "0381eb9a9a9ec739d527c1631b31b421566f5c2a47b4ab5b1f6a686dfb68eab716"
-                .to_string(),
-            change_label: "3e9fce73d4e77a4809908e3c3a2e54ee147b9312dc5044a193d1fc85de46e3c1"
-                .to_string(),
-            network: "mainnet".to_string(),
-            b_scan: "04b2a411635c097759aacd0f005a4c82c8c92862c6fc284b80b8efebc20c3d17".to_string(),
+            birthday_height: 100,
+            labels: vec![
+                LabelRequest::Index(1),
+                LabelRequest::Tweak(
+                    "D58C41E1CA930813BD9EFAC75B52F2CBFF72A1EF98F6849878970F7DBB910E89".to_string(),
+                ),
+            ],
+            ..registration_request()
         };
 
         let result = service.register_client(request).await;
@@ -87,5 +447,98 @@ mod tests {
         let response = result.unwrap();
         assert!(!response.client_id.is_empty());
         assert!(!response.receiving_address.is_empty());
+        assert_eq!(response.webhook_secret, None);
+
+        // Label 1 is derived from the scan key, and label 2 was given as its
+        // tweak. Each gets its own address.
+        let tweaks: Vec<_> = response
+            .labels
+            .iter()
+            .map(|label| (label.m, label.tweak.as_str()))
+            .collect();
+        assert_eq!(
+            tweaks,
+            vec![
+                (
+                    Some(1),
+                    "e74fb744d3c8efb735bdd37af21fe588bb6d38f061884a8d62ce19577933b706"
+                ),
+                (
+                    None,
+                    "d58c41e1ca930813bd9efac75b52f2cbff72a1ef98f6849878970f7dbb910e89"
+                ),
+            ]
+        );
+        assert_ne!(response.labels[0].address, response.receiving_address);
+        assert_ne!(response.labels[0].address, response.labels[1].address);
+
+        // Only the issued token authenticates the client
+        service
+            .authenticate(&response.client_id, &response.api_token)
+            .await
+            .unwrap();
+        assert!(matches!(
+            service.authenticate(&response.client_id, "wrong").await,
+            Err(Error::Unauthorized)
+        ));
+        assert!(matches!(
+            service.authenticate("unknown", &response.api_token).await,
+            Err(Error::Unauthorized)
+        ));
+        let stored = service.get_client_data(&response.client_id).await.unwrap();
+        assert_eq!(stored.token_hash, token_hash(&response.api_token));
+
+        // The public info matches the registration and leaves out the scan key
+        let info = service.get_client_info(&response.client_id).await.unwrap();
+        assert_eq!(info.receiving_address, response.receiving_address);
+        assert_eq!(info.network, "mainnet");
+        assert_eq!(info.birthday_height, 100);
+        assert_eq!(info.last_scanned_height, None);
+        assert_eq!(
+            info.change_label,
+            "3e9fce73d4e77a4809908e3c3a2e54ee147b9312dc5044a193d1fc85de46e3c1"
+        );
+        assert_eq!(info.labels, response.labels);
+        assert!(!serde_json::to_string(&info).unwrap().contains("04b2a411"));
+
+        let change_label =
+            "0000000000000000000000000000000000000000000000000000000000000001".to_string();
+        let updated = service
+            .update_client(
+                &response.client_id,
+                ClientUpdateRequest {
+                    change_label: Some(change_label.clone()),
+                    labels: Some(vec![LabelRequest::Index(2)]),
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(updated.change_label, change_label);
+        assert_eq!(updated.labels.len(), 1);
+        assert_eq!(updated.labels[0].m, Some(2));
+        assert_eq!(updated.labels[0].tweak, response.labels[1].tweak);
+        assert_eq!(updated.labels[0].address, response.labels[1].address);
+        assert!(matches!(
+            service
+                .update_client(
+                    &response.client_id,
+                    ClientUpdateRequest {
+                        labels: Some(vec![LabelRequest::Index(0)]),
+                        ..Default::default()
+                    },
+                )
+                .await,
+            Err(Error::InvalidInput(_))
+        ));
+        assert_eq!(
+            service.get_client_info(&response.client_id).await.unwrap(),
+            updated
+        );
+
+        service.delete_client(&response.client_id).await.unwrap();
+        assert!(matches!(
+            service.get_client_info(&response.client_id).await,
+            Err(Error::ClientNotFound)
+        ));
     }
 }
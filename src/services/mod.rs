@@ -1,8 +1,56 @@
 mod client_service;
 mod scan_service;
+mod scan_worker;
 mod utxo_service;
+mod webhook_worker;
 
 pub use client_service::ClientService;
 pub use scan_service::ScanService;
+pub use scan_worker::ScanWorker;
 pub use utxo_service::UtxoService;
+pub use webhook_worker::WebhookWorker;
 
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::models::{RegistrationRequest, UTXO};
+
+    /// Registration of the wallet the service tests scan for. The outputs
+    /// they index are paid to it.
+    pub(crate) fn registration_request() -> RegistrationRequest {
+        RegistrationRequest {
+            version: 0,
+            scan_pubkey: "03bbc63f12745d3b9e9d24c6cd7a1efebad0a7f469232fbecf31fba7b4f7ddeda8"
+                .to_string(),
+            spend_pubkey: "0381eb9a9a9ec739d527c1631b31b421566f5c2a47b4ab5b1f6a686dfb68eab716"
+                .to_string(),
+            change_label: "3e9fce73d4e77a4809908e3c3a2e54ee147b9312dc5044a193d1fc85de46e3c1"
+                .to_string(),
+            network: "mainnet".to_string(),
+            b_scan: "04b2a411635c097759aacd0f005a4c82c8c92862c6fc284b80b8efebc20c3d17".to_string(),
+            birthday_height: 0,
+            webhook_url: None,
+            labels: vec![],
+        }
+    }
+
+    /// An output paid to the wallet of `registration_request`.
+    pub(crate) fn paid_utxo() -> UTXO {
+        UTXO {
+            txid: [1; 32],
+            vout: 0,
+            amount: 100000,
+            script_pubkey: hex::decode(
+                "596b20b0f02f9b085a801ee276ce9f21470c0d30b633372617c564a2a2fda171",
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+            input_tweak: hex::decode(
+                "020d8ec185ece237b30d2064da3700aaf42519d60ddcb0a76695b3eada2d23b319",
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        }
+    }
+}
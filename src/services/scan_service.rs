@@ -1,11 +1,22 @@
 // src/core/services/scan_service.rs
 use crate::compute::Compute;
-use crate::models::{ScanRequest, TweakRequest, UTXO};
+use crate::indexer::IndexEvent;
+use crate::models::{
+    BlockFilter, ClientBalance, ClientData, ClientOutput, ClientOutputsPage, ClientScanResponse,
+    FoundOutput, IndexedOutput, ScanRequest, TweakRequest, TweakResponse, TweakStreamEvent,
+    UtxoWithSpend, UTXO,
+};
 use crate::services::{ClientService, UtxoService};
 use crate::storage::{ClientStore, UtxoStore};
-use crate::Result;
-use silentpayments::secp256k1::SecretKey;
+use crate::{Error, Result};
+use bitcoin::hashes::Hash;
+use bitcoin::BlockHash;
+use futures_util::{stream, Stream, StreamExt};
 use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Largest page of found outputs served at once.
+pub const MAX_OUTPUTS_PAGE: usize = 1000;
 
 pub struct ScanService<S: UtxoStore + ClientStore + Send + Sync, C: Compute> {
     utxo_service: Arc<UtxoService<S>>,
@@ -26,20 +37,588 @@ impl<S: UtxoStore + ClientStore + Send + Sync, C: Compute> ScanService<S, C> {
         }
     }
 
-    pub async fn scan_utxos(&self, request: ScanRequest) -> Result<Vec<UTXO>> {
+    /// Scans one block for the client. Like those of `scan_new_blocks`, the
+    /// matches are added to the client's found outputs, unless the block is
+    /// below the client's birthday.
+    pub async fn scan_utxos(&self, request: ScanRequest) -> Result<Vec<ClientOutput>> {
         let utxos = self.utxo_service.query_utxos(request.block_height).await?;
         let client_data = self
             .client_service
             .get_client_data(&request.client_id)
             .await?;
-        let b_scan = SecretKey::from_slice(&client_data.b_scan)?;
+        let found = self
+            .scan_block(
+                &request.client_id,
+                &client_data,
+                request.block_height,
+                &utxos,
+            )
+            .await?;
+        self.with_spends(found, request.unspent_only).await
+    }
 
-        self.compute_service
-            .perform_ecdh(&utxos, &client_data.receiver, &b_scan)
-            .await
+    /// Scans the blocks after the client's last scanned height, or from its
+    /// birthday, up to the indexed tip and advances its marker. At most
+    /// `max_range_span` blocks are scanned per call.
+    pub async fn scan_new_blocks(
+        &self,
+        client_id: &str,
+        unspent_only: bool,
+    ) -> Result<ClientScanResponse> {
+        let client_data = self.client_service.get_client_data(client_id).await?;
+        let start_height = client_data
+            .last_scanned_height
+            .map_or(0, |height| height + 1)
+            .max(client_data.info.birthday_height);
+        let tip_height = self.utxo_service.tip_height().await?;
+        let Some(tip) = tip_height.filter(|tip| *tip >= start_height) else {
+            return Ok(ClientScanResponse {
+                last_scanned_height: client_data.last_scanned_height,
+                tip_height,
+                utxos: Vec::new(),
+            });
+        };
+        let span = self.utxo_service.max_range_span().max(1);
+        let end_height = tip.min(start_height.saturating_add(span - 1));
+
+        let mut found = Vec::new();
+        for (height, utxos) in self
+            .utxo_service
+            .query_blocks_utxos(start_height, end_height)
+            .await?
+        {
+            found.extend(
+                self.scan_block(client_id, &client_data, height, &utxos)
+                    .await?,
+            );
+        }
+        let utxos = self.with_spends(found, unspent_only).await?;
+        self.client_service
+            .set_last_scanned_height(client_id, end_height)
+            .await?;
+
+        Ok(ClientScanResponse {
+            last_scanned_height: Some(end_height),
+            tip_height,
+            utxos,
+        })
+    }
+
+    /// Returns the client's matches among `utxos`, the outputs of the block
+    /// at `height`, and adds them to its found outputs.
+    async fn scan_block(
+        &self,
+        client_id: &str,
+        client_data: &ClientData,
+        height: u64,
+        utxos: &[UTXO],
+    ) -> Result<Vec<FoundOutput>> {
+        let matches = self
+            .compute_service
+            .perform_ecdh(utxos, &client_data.receiver, &client_data.b_scan)
+            .await?;
+        let found = matches
+            .iter()
+            .map(|m| FoundOutput {
+                height,
+                utxo: m.utxo.clone(),
+                label: m.label.clone(),
+            })
+            .collect();
+        if !matches.is_empty() && height >= client_data.info.birthday_height {
+            self.client_service
+                .add_found_outputs(client_id, height, matches)
+                .await?;
+        }
+        Ok(found)
+    }
+
+    /// Looks up the spends of found outputs, leaving out the spent ones if
+    /// `unspent_only`.
+    async fn with_spends(
+        &self,
+        found: Vec<FoundOutput>,
+        unspent_only: bool,
+    ) -> Result<Vec<ClientOutput>> {
+        let utxos = found.iter().map(|found| found.utxo.clone()).collect();
+        let outputs = self.utxo_service.with_spends(utxos, false).await?;
+        Ok(found
+            .into_iter()
+            .zip(outputs)
+            .filter(|(_, output)| !unspent_only || output.spent.is_none())
+            .map(|(found, output)| ClientOutput {
+                height: found.height,
+                label: found.label,
+                output,
+            })
+            .collect())
+    }
+
+    /// Returns up to `limit` of the client's found outputs, starting after
+    /// the `next` cursor of a previous page.
+    pub async fn client_outputs(
+        &self,
+        client_id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<ClientOutputsPage> {
+        if limit > MAX_OUTPUTS_PAGE {
+            return Err(Error::InvalidInput(format!(
+                "limit of {} exceeds the maximum of {}",
+                limit, MAX_OUTPUTS_PAGE
+            )));
+        }
+        let after = after.map(parse_outputs_cursor).transpose()?;
+        let page = self
+            .client_service
+            .found_outputs_page(client_id, after, limit)
+            .await?;
+        let next = match page.last() {
+            Some(last) if page.len() == limit => Some(outputs_cursor(last)),
+            _ => None,
+        };
+        let outputs = self.with_spends(page, false).await?;
+        Ok(ClientOutputsPage { outputs, next })
     }
 
-    pub async fn get_tweaks(&self, request: TweakRequest) -> Result<Vec<UTXO>> {
+    /// Sums the client's found outputs by whether they are spent.
+    pub async fn client_balance(&self, client_id: &str) -> Result<ClientBalance> {
+        let found = self.client_service.get_found_outputs(client_id).await?;
+        let mut balance = ClientBalance {
+            tip_height: self.utxo_service.tip_height().await?,
+            ..Default::default()
+        };
+        let utxos = found.into_iter().map(|found| found.utxo).collect();
+        for output in self.utxo_service.with_spends(utxos, false).await? {
+            if output.spent.is_some() {
+                balance.spent += output.utxo.amount;
+                balance.spent_outputs += 1;
+            } else {
+                balance.unspent += output.utxo.amount;
+                balance.unspent_outputs += 1;
+            }
+        }
+        Ok(balance)
+    }
+
+    pub async fn get_tweaks(&self, request: TweakRequest) -> Result<TweakResponse<UtxoWithSpend>> {
         self.utxo_service.query_utxos_range(&request).await
     }
+
+    pub async fn get_block_tweaks(
+        &self,
+        block_height: u64,
+        cut_through: bool,
+    ) -> Result<Vec<[u8; 33]>> {
+        self.utxo_service
+            .block_tweaks(block_height, cut_through)
+            .await
+    }
+
+    /// Streams the tweaks of each block indexed from now on. When resuming
+    /// after `last_block`, the indexed blocks above it are sent first,
+    /// preceded by a rollback if it is no longer in the index. Resuming more
+    /// than `max_range_span` blocks behind the tip is refused. The stream
+    /// ends after an error.
+    pub async fn tweak_stream(
+        self: Arc<Self>,
+        last_block: Option<(u64, [u8; 32])>,
+        cut_through: bool,
+    ) -> Result<impl Stream<Item = Result<TweakStreamEvent>>> {
+        // Subscribed before the first poll, so no block is missed in between
+        let events = self.utxo_service.subscribe();
+        let mut rolled_back = None;
+        let mut next_height = None;
+        if let Some((height, hash)) = last_block {
+            let tip_height = self.utxo_service.tip_height().await?.unwrap_or(0);
+            let max_span = self.utxo_service.max_range_span();
+            if tip_height.saturating_sub(height) > max_span {
+                return Err(Error::InvalidInput(format!(
+                    "cannot resume more than {} blocks behind the tip",
+                    max_span
+                )));
+            }
+            next_height = Some(height + 1);
+            let info = self.utxo_service.get_block_info(height).await?;
+            if info.map_or(true, |info| info.hash != hash) {
+                // Only the last block sent is known to the client, so roll
+                // back to just below it
+                let height = height.saturating_sub(1).min(tip_height);
+                rolled_back = self.rolled_back_event(height).await?;
+                next_height = Some(height + 1);
+            }
+        }
+        let events = stream::unfold(Some((self, events, next_height)), move |state| async move {
+            let (service, mut events, mut next_height) = state?;
+            match service
+                .next_tweak_event(&mut events, &mut next_height, cut_through)
+                .await
+            {
+                Ok(Some(event)) => Some((Ok(event), Some((service, events, next_height)))),
+                Ok(None) => None,
+                Err(e) => Some((Err(e), None)),
+            }
+        });
+        Ok(stream::iter(rolled_back.map(Ok)).chain(events))
+    }
+
+    /// The rollback to `height`, if a block is still indexed there.
+    async fn rolled_back_event(&self, height: u64) -> Result<Option<TweakStreamEvent>> {
+        Ok(self.utxo_service.get_block_info(height).await?.map(|info| {
+            TweakStreamEvent::RolledBack {
+                height,
+                block_hash: BlockHash::from_byte_array(info.hash).to_string(),
+            }
+        }))
+    }
+
+    /// Returns the block at `next_height` once it is indexed, or a rollback
+    /// below it. Blocks whose events were missed are read from the index.
+    async fn next_tweak_event(
+        &self,
+        events: &mut broadcast::Receiver<IndexEvent>,
+        next_height: &mut Option<u64>,
+        cut_through: bool,
+    ) -> Result<Option<TweakStreamEvent>> {
+        loop {
+            if let Some(height) = *next_height {
+                let tip_height = self.utxo_service.tip_height().await?;
+                if tip_height.is_some_and(|tip| tip >= height) {
+                    *next_height = Some(height + 1);
+                    // Only missing if it was rolled back in the meantime
+                    let Some(info) = self.utxo_service.get_block_info(height).await? else {
+                        continue;
+                    };
+                    let tweaks = self.get_block_tweaks(height, cut_through).await?;
+                    return Ok(Some(TweakStreamEvent::Block {
+                        height,
+                        block_hash: BlockHash::from_byte_array(info.hash).to_string(),
+                        tweaks: tweaks.iter().map(hex::encode).collect(),
+                    }));
+                }
+            }
+            match events.recv().await {
+                Ok(IndexEvent::BlockIndexed { height, .. }) => {
+                    next_height.get_or_insert(height);
+                }
+                Ok(IndexEvent::RolledBack { height }) => {
+                    *next_height = next_height.map(|next| next.min(height + 1));
+                    // Missing if rolled back further meanwhile, which is
+                    // sent next
+                    if let Some(event) = self.rolled_back_event(height).await? {
+                        return Ok(Some(event));
+                    }
+                }
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return Ok(None),
+            }
+        }
+    }
+
+    pub async fn get_utxo(&self, txid: [u8; 32], vout: u32) -> Result<Option<IndexedOutput>> {
+        self.utxo_service.get_utxo(txid, vout).await
+    }
+
+    pub async fn get_filter(&self, block_height: u64) -> Result<Option<BlockFilter>> {
+        self.utxo_service.get_filter(block_height).await
+    }
+
+    pub async fn get_filters(
+        &self,
+        start_height: u64,
+        end_height: u64,
+    ) -> Result<Vec<(u64, BlockFilter)>> {
+        self.utxo_service
+            .get_filters(start_height, end_height)
+            .await
+    }
+}
+
+/// Encodes where a page of found outputs ends as the hex of the last
+/// output's big-endian height, txid and big-endian vout.
+fn outputs_cursor(output: &FoundOutput) -> String {
+    let mut cursor = Vec::with_capacity(8 + 32 + 4);
+    cursor.extend_from_slice(&output.height.to_be_bytes());
+    cursor.extend_from_slice(&output.utxo.txid);
+    cursor.extend_from_slice(&output.utxo.vout.to_be_bytes());
+    hex::encode(cursor)
+}
+
+fn parse_outputs_cursor(cursor: &str) -> Result<(u64, [u8; 32], u32)> {
+    let mut bytes = [0u8; 8 + 32 + 4];
+    hex::decode_to_slice(cursor, &mut bytes)
+        .map_err(|_| Error::InvalidInput("invalid outputs cursor".to_string()))?;
+    let (height, rest) = bytes.split_at(8);
+    let (txid, vout) = rest.split_at(32);
+    Ok((
+        u64::from_be_bytes(height.try_into().unwrap()),
+        txid.try_into().unwrap(),
+        u32::from_be_bytes(vout.try_into().unwrap()),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compute::LocalCompute;
+    use crate::models::{BlockInfo, IndexedBlock, RegistrationRequest, SpentInfo};
+    use crate::services::tests::{paid_utxo, registration_request};
+    use crate::storage::MemoryStore;
+    use futures_util::StreamExt;
+
+    #[tokio::test]
+    async fn test_scan_new_blocks() {
+        let store = Arc::new(MemoryStore::new());
+        let utxo_service = Arc::new(UtxoService::new(store.clone(), 1));
+        let client_service = Arc::new(ClientService::new(store.clone()));
+        let scan_service = ScanService::new(
+            utxo_service.clone(),
+            client_service.clone(),
+            Arc::new(LocalCompute::new()),
+        );
+        let client_id = client_service
+            .register_client(RegistrationRequest {
+                birthday_height: 100,
+                ..registration_request()
+            })
+            .await
+            .unwrap()
+            .client_id;
+        let utxo = paid_utxo();
+        // The same output before the birthday is never scanned
+        utxo_service
+            .add_block(IndexedBlock {
+                height: 99,
+                utxos: vec![UTXO {
+                    vout: 1,
+                    ..utxo.clone()
+                }],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        utxo_service
+            .add_block(IndexedBlock {
+                height: 100,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        utxo_service
+            .add_block(IndexedBlock {
+                height: 101,
+                utxos: vec![utxo.clone()],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        // One block per call with a span of one
+        let response = scan_service
+            .scan_new_blocks(&client_id, false)
+            .await
+            .unwrap();
+        assert_eq!(response.last_scanned_height, Some(100));
+        assert_eq!(response.tip_height, Some(101));
+        assert!(response.utxos.is_empty());
+
+        let response = scan_service
+            .scan_new_blocks(&client_id, false)
+            .await
+            .unwrap();
+        assert_eq!(response.last_scanned_height, Some(101));
+        assert_eq!(response.utxos.len(), 1);
+        assert_eq!(response.utxos[0].height, 101);
+        assert_eq!(response.utxos[0].output.utxo, utxo);
+
+        // Nothing new until the next block
+        let response = scan_service
+            .scan_new_blocks(&client_id, false)
+            .await
+            .unwrap();
+        assert_eq!(response.last_scanned_height, Some(101));
+        assert!(response.utxos.is_empty());
+        let info = client_service.get_client_info(&client_id).await.unwrap();
+        assert_eq!(info.last_scanned_height, Some(101));
+
+        // A block queried below the birthday returns its matches without
+        // keeping them
+        let outputs = scan_service
+            .scan_utxos(ScanRequest {
+                block_height: 99,
+                client_id: client_id.clone(),
+                unspent_only: false,
+                with_spent_info: false,
+            })
+            .await
+            .unwrap();
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].height, 99);
+
+        // Matches are kept as found outputs, and counted once spent
+        let page = scan_service
+            .client_outputs(&client_id, None, 10)
+            .await
+            .unwrap();
+        assert_eq!(page.outputs.len(), 1);
+        assert_eq!(page.outputs[0].height, 101);
+        assert_eq!(page.outputs[0].output.utxo, utxo);
+        assert_eq!(page.next, None);
+        let page = scan_service
+            .client_outputs(&client_id, None, 1)
+            .await
+            .unwrap();
+        assert_eq!(page.outputs.len(), 1);
+        let next = page.next.unwrap();
+        assert!(scan_service
+            .client_outputs(&client_id, Some(&next), 1)
+            .await
+            .unwrap()
+            .outputs
+            .is_empty());
+        assert!(scan_service
+            .client_outputs(&client_id, Some("00"), 10)
+            .await
+            .is_err());
+        assert!(scan_service
+            .client_outputs(&client_id, None, MAX_OUTPUTS_PAGE + 1)
+            .await
+            .is_err());
+        let balance = scan_service.client_balance(&client_id).await.unwrap();
+        assert_eq!((balance.unspent, balance.unspent_outputs), (100000, 1));
+        store
+            .add_block(IndexedBlock {
+                height: 102,
+                spends: vec![(
+                    utxo.txid,
+                    utxo.vout,
+                    SpentInfo {
+                        height: 102,
+                        txid: [2; 32],
+                    },
+                )],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let balance = scan_service.client_balance(&client_id).await.unwrap();
+        assert_eq!((balance.unspent, balance.spent_outputs), (0, 1));
+    }
+
+    #[tokio::test]
+    async fn test_tweak_stream() {
+        let store = Arc::new(MemoryStore::new());
+        let utxo_service = Arc::new(UtxoService::new(store.clone(), 2));
+        let scan_service = Arc::new(ScanService::new(
+            utxo_service.clone(),
+            Arc::new(ClientService::new(store.clone())),
+            Arc::new(LocalCompute::new()),
+        ));
+        let index_block = |height: u64, hash: u8| {
+            let store = store.clone();
+            async move {
+                let utxo = UTXO {
+                    txid: [hash; 32],
+                    vout: 0,
+                    amount: 1000,
+                    script_pubkey: [hash; 32],
+                    input_tweak: [hash; 33],
+                };
+                store
+                    .add_block(IndexedBlock {
+                        height,
+                        info: BlockInfo {
+                            hash: [hash; 32],
+                            prev_hash: [0; 32],
+                        },
+                        utxos: vec![utxo],
+                        ..Default::default()
+                    })
+                    .await
+                    .unwrap();
+            }
+        };
+        let block = |height: u64, hash: u8| TweakStreamEvent::Block {
+            height,
+            block_hash: BlockHash::from_byte_array([hash; 32]).to_string(),
+            tweaks: vec![hex::encode([hash; 33])],
+        };
+        let rolled_back = |height: u64, hash: u8| TweakStreamEvent::RolledBack {
+            height,
+            block_hash: BlockHash::from_byte_array([hash; 32]).to_string(),
+        };
+        index_block(1, 1).await;
+        index_block(2, 2).await;
+
+        // A resumed stream first catches up from the index
+        let events = utxo_service.index_events();
+        let mut stream = Box::pin(
+            scan_service
+                .clone()
+                .tweak_stream(Some((1, [1; 32])), false)
+                .await
+                .unwrap(),
+        );
+        assert_eq!(stream.next().await.unwrap().unwrap(), block(2, 2));
+
+        // Then follows the indexer, including its rollbacks
+        index_block(3, 3).await;
+        events
+            .send(IndexEvent::BlockIndexed {
+                height: 3,
+                block_hash: [3; 32],
+            })
+            .unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap(), block(3, 3));
+        store.rollback_to(2).await.unwrap();
+        events.send(IndexEvent::RolledBack { height: 2 }).unwrap();
+        index_block(3, 4).await;
+        events
+            .send(IndexEvent::BlockIndexed {
+                height: 3,
+                block_hash: [4; 32],
+            })
+            .unwrap();
+        let event = stream.next().await.unwrap().unwrap();
+        assert_eq!(event, rolled_back(2, 2));
+        assert_eq!(
+            event.id(),
+            format!("2:{}", BlockHash::from_byte_array([2; 32]))
+        );
+        assert_eq!(stream.next().await.unwrap().unwrap(), block(3, 4));
+
+        // Resuming after a block that was replaced starts with its rollback
+        let mut stream = Box::pin(
+            scan_service
+                .clone()
+                .tweak_stream(Some((3, [3; 32])), false)
+                .await
+                .unwrap(),
+        );
+        assert_eq!(stream.next().await.unwrap().unwrap(), rolled_back(2, 2));
+        assert_eq!(stream.next().await.unwrap().unwrap(), block(3, 4));
+
+        // A fresh stream only sees new blocks
+        let mut stream = Box::pin(
+            scan_service
+                .clone()
+                .tweak_stream(None, false)
+                .await
+                .unwrap(),
+        );
+        index_block(4, 5).await;
+        events
+            .send(IndexEvent::BlockIndexed {
+                height: 4,
+                block_hash: [5; 32],
+            })
+            .unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap(), block(4, 5));
+
+        // Resuming too far behind the tip is refused
+        assert!(matches!(
+            scan_service.tweak_stream(Some((1, [1; 32])), false).await,
+            Err(Error::InvalidInput(_))
+        ));
+    }
 }
@@ -0,0 +1,510 @@
+use crate::compute::Compute;
+use crate::indexer::IndexEvent;
+use crate::models::{ClientEvent, ClientNotification, FoundOutput, IndexState, UTXO};
+use crate::services::{ClientService, UtxoService};
+use crate::storage::{ClientStore, UtxoStore};
+use crate::{Error, Result};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Number of recently scanned block hashes kept to find where the index
+/// forked off after rollback events were missed.
+const RECENT_BLOCKS: usize = 100;
+
+/// Scans every newly indexed block for all registered clients and stores
+/// their matches as found outputs, so wallets get them without scanning.
+/// Matches and their invalidation by reorgs are also sent as client
+/// notifications.
+///
+/// The last scanned block is persisted, so blocks indexed while the worker
+/// was behind or not running are scanned when it catches up with the index.
+pub struct ScanWorker<S: UtxoStore + ClientStore + Send + Sync, C: Compute> {
+    utxo_service: Arc<UtxoService<S>>,
+    client_service: Arc<ClientService<S>>,
+    compute_service: Arc<C>,
+}
+
+impl<S: UtxoStore + ClientStore + Send + Sync, C: Compute> ScanWorker<S, C> {
+    pub fn new(
+        utxo_service: Arc<UtxoService<S>>,
+        client_service: Arc<ClientService<S>>,
+        compute_service: Arc<C>,
+    ) -> Self {
+        Self {
+            utxo_service,
+            client_service,
+            compute_service,
+        }
+    }
+
+    /// Handles index events until the indexer drops its sender.
+    pub async fn run(&self, mut events: broadcast::Receiver<IndexEvent>) {
+        // Hashes of the blocks scanned since the worker started, by height
+        let mut recent = BTreeMap::new();
+        if let Err(e) = self.catch_up(None, &mut recent).await {
+            log::error!("background scan failed: {}", e);
+        }
+        loop {
+            let result = match events.recv().await {
+                Ok(IndexEvent::BlockIndexed { height, .. }) => {
+                    self.catch_up(Some(height), &mut recent).await
+                }
+                Ok(IndexEvent::RolledBack { height }) => self.rollback(height, &mut recent).await,
+                Err(RecvError::Lagged(missed)) => {
+                    log::warn!(
+                        "scan worker missed {} index events, catching up with the index",
+                        missed
+                    );
+                    self.catch_up(None, &mut recent).await
+                }
+                Err(RecvError::Closed) => return,
+            };
+            if let Err(e) = result {
+                log::error!("background scan failed: {}", e);
+            }
+        }
+    }
+
+    /// Rolls back what was scanned of blocks no longer in the index, then
+    /// scans every block after the last scanned one up to the indexed tip.
+    /// Before anything was scanned, scanning starts at `first_height`, if
+    /// given.
+    async fn catch_up(
+        &self,
+        first_height: Option<u64>,
+        recent: &mut BTreeMap<u64, [u8; 32]>,
+    ) -> Result<()> {
+        let Some(tip_height) = self.utxo_service.tip_height().await? else {
+            return Ok(());
+        };
+        let start_height = match self.client_service.scan_state().await? {
+            Some(scanned) => {
+                let fork_height = self.fork_height(scanned, recent).await?;
+                if fork_height < scanned.height {
+                    self.rollback(fork_height, recent).await?;
+                }
+                fork_height + 1
+            }
+            None => match first_height {
+                Some(height) => height,
+                None => return Ok(()),
+            },
+        };
+        self.retry_failed_scans().await?;
+        for height in start_height..=tip_height {
+            // Rolled back since the tip was read
+            let Some(block) = self.utxo_service.get_block_info(height).await? else {
+                break;
+            };
+            let found = self.scan_block(height).await?;
+            log::debug!("found {} client outputs at height {}", found, height);
+            self.client_service
+                .set_scan_state(Some(IndexState {
+                    height,
+                    block_hash: block.hash,
+                }))
+                .await?;
+            recent.insert(height, block.hash);
+            if recent.len() > RECENT_BLOCKS {
+                recent.pop_first();
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the height of the last scanned block that is still in the
+    /// index. If none of the blocks whose hashes are known is, the blocks
+    /// below them are assumed to be unchanged.
+    async fn fork_height(
+        &self,
+        scanned: IndexState,
+        recent: &BTreeMap<u64, [u8; 32]>,
+    ) -> Result<u64> {
+        let mut known = recent.clone();
+        known.insert(scanned.height, scanned.block_hash);
+        for (&height, hash) in known.range(..=scanned.height).rev() {
+            let block = self.utxo_service.get_block_info(height).await?;
+            if block.is_some_and(|block| block.hash == *hash) {
+                return Ok(height);
+            }
+        }
+        let oldest = known.keys().next().copied().unwrap_or(scanned.height);
+        log::warn!(
+            "no scanned block since height {} is still indexed, rescanning from there",
+            oldest
+        );
+        Ok(oldest.saturating_sub(1))
+    }
+
+    async fn rollback(&self, height: u64, recent: &mut BTreeMap<u64, [u8; 32]>) -> Result<()> {
+        let mut invalidated: BTreeMap<String, Vec<FoundOutput>> = BTreeMap::new();
+        for (client_id, output) in self.client_service.rollback(height).await? {
+            invalidated.entry(client_id).or_default().push(output);
+        }
+        for (client_id, outputs) in invalidated {
+            self.client_service
+                .notify(ClientEvent {
+                    client_id,
+                    notification: ClientNotification::Invalidated { height, outputs },
+                })
+                .await?;
+        }
+        recent.retain(|scanned, _| *scanned <= height);
+        let scanned = self.client_service.scan_state().await?;
+        if scanned.is_some_and(|scanned| scanned.height > height) {
+            let block = self.utxo_service.get_block_info(height).await?;
+            self.client_service
+                .set_scan_state(block.map(|block| IndexState {
+                    height,
+                    block_hash: block.hash,
+                }))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Scans the blocks again that could not be scanned for a client, until
+    /// they succeed or the client is deleted.
+    async fn retry_failed_scans(&self) -> Result<()> {
+        for (height, client_id) in self.client_service.failed_scans().await? {
+            let utxos = self.utxo_service.query_utxos(height).await?;
+            match self.scan_client(&client_id, height, &utxos).await {
+                Ok(_) | Err(Error::ClientNotFound) => {
+                    self.client_service
+                        .remove_failed_scan(&client_id, height)
+                        .await?
+                }
+                Err(e) => log::warn!(
+                    "scanning block {} for client {} failed again: {}",
+                    height,
+                    client_id,
+                    e
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    /// Scans the block at `height` for every registered client whose
+    /// birthday it is not below, returning the number of outputs found.
+    /// A client that fails to be scanned is logged and recorded, so the
+    /// block is scanned for it again on the next catch up.
+    pub async fn scan_block(&self, height: u64) -> Result<usize> {
+        let utxos = self.utxo_service.query_utxos(height).await?;
+        if utxos.is_empty() {
+            return Ok(0);
+        }
+        let mut found = 0;
+        for client_id in self.client_service.list_clients().await? {
+            match self.scan_client(&client_id, height, &utxos).await {
+                Ok(count) => found += count,
+                // Deleted since it was listed
+                Err(Error::ClientNotFound) => {}
+                Err(e) => {
+                    log::error!(
+                        "scanning block {} for client {} failed, retrying later: {}",
+                        height,
+                        client_id,
+                        e
+                    );
+                    self.client_service
+                        .add_failed_scan(&client_id, height)
+                        .await?;
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// Scans `utxos`, the outputs of the block at `height`, for a client,
+    /// returning the number of outputs found.
+    async fn scan_client(&self, client_id: &str, height: u64, utxos: &[UTXO]) -> Result<usize> {
+        let client_data = self.client_service.get_client_data(client_id).await?;
+        if height < client_data.info.birthday_height {
+            return Ok(0);
+        }
+        let matches = self
+            .compute_service
+            .perform_ecdh(utxos, &client_data.receiver, &client_data.b_scan)
+            .await?;
+        if matches.is_empty() {
+            return Ok(0);
+        }
+        let found = matches.len();
+        let outputs = matches
+            .iter()
+            .map(|m| FoundOutput {
+                height,
+                utxo: m.utxo.clone(),
+                label: m.label.clone(),
+            })
+            .collect();
+        self.client_service
+            .add_found_outputs(client_id, height, matches)
+            .await?;
+        self.client_service
+            .notify(ClientEvent {
+                client_id: client_id.to_string(),
+                notification: ClientNotification::Found { height, outputs },
+            })
+            .await?;
+        Ok(found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compute::LocalCompute;
+    use crate::models::{BlockInfo, IndexedBlock, MatchedOutput, ScanSecret};
+    use crate::services::tests::{paid_utxo, registration_request};
+    use crate::services::ScanService;
+    use crate::storage::MemoryStore;
+    use async_trait::async_trait;
+    use silentpayments::receiving::Receiver;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_scan_worker() {
+        let store = Arc::new(MemoryStore::new());
+        let utxo_service = Arc::new(UtxoService::new(store.clone(), 10));
+        let client_service = Arc::new(ClientService::new(store.clone()));
+        let compute = Arc::new(LocalCompute::new());
+        let worker = ScanWorker::new(
+            utxo_service.clone(),
+            client_service.clone(),
+            compute.clone(),
+        );
+        let client_id = client_service
+            .register_client(registration_request())
+            .await
+            .unwrap()
+            .client_id;
+        let utxo = paid_utxo();
+        utxo_service
+            .add_block(IndexedBlock {
+                height: 1,
+                utxos: vec![utxo.clone()],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        utxo_service
+            .add_block(IndexedBlock {
+                height: 2,
+                utxos: vec![UTXO {
+                    vout: 1,
+                    ..utxo.clone()
+                }],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let mut notifications = client_service.subscribe();
+        let (events, receiver) = broadcast::channel(16);
+        for height in [1, 2] {
+            events
+                .send(IndexEvent::BlockIndexed {
+                    height,
+                    block_hash: [height as u8; 32],
+                })
+                .unwrap();
+        }
+        drop(events);
+        worker.run(receiver).await;
+        let found = client_service.get_found_outputs(&client_id).await.unwrap();
+        assert_eq!(found.len(), 2);
+        assert_eq!((found[0].height, &found[0].utxo), (1, &utxo));
+        assert_eq!(found[1].height, 2);
+
+        // Each match is pushed once found
+        let pushed: Vec<_> = std::iter::from_fn(|| notifications.try_recv().ok()).collect();
+        assert_eq!(pushed.len(), 2);
+        assert!(pushed.iter().all(|event| event.client_id == client_id));
+        assert!(matches!(
+            &pushed[0].notification,
+            ClientNotification::Found { height: 1, outputs } if outputs[0].utxo == utxo
+        ));
+        assert!(matches!(
+            &pushed[1].notification,
+            ClientNotification::Found { height: 2, .. }
+        ));
+
+        // A reorg drops the outputs found and the blocks scanned above it,
+        // and invalidates the outputs found for them
+        ScanService::new(utxo_service.clone(), client_service.clone(), compute)
+            .scan_new_blocks(&client_id, false)
+            .await
+            .unwrap();
+        let (events, receiver) = broadcast::channel(16);
+        events.send(IndexEvent::RolledBack { height: 1 }).unwrap();
+        drop(events);
+        worker.run(receiver).await;
+        let pushed: Vec<_> = std::iter::from_fn(|| notifications.try_recv().ok()).collect();
+        assert_eq!(pushed.len(), 1);
+        match &pushed[0].notification {
+            ClientNotification::Invalidated { height, outputs } => {
+                assert_eq!(*height, 1);
+                assert_eq!(outputs.len(), 1);
+                assert_eq!((outputs[0].height, outputs[0].utxo.vout), (2, 1));
+            }
+            notification => panic!("unexpected notification {:?}", notification),
+        }
+        let remaining = client_service.get_found_outputs(&client_id).await.unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].height, 1);
+        let info = client_service.get_client_info(&client_id).await.unwrap();
+        assert_eq!(info.last_scanned_height, Some(1));
+        assert_eq!(
+            client_service.scan_state().await.unwrap(),
+            Some(IndexState {
+                height: 1,
+                block_hash: [0; 32],
+            })
+        );
+
+        // Blocks replaced while the worker was not running are rolled back
+        // when it starts, and the blocks after them scanned again
+        store.rollback_to(0).await.unwrap();
+        utxo_service
+            .add_block(IndexedBlock {
+                height: 1,
+                info: BlockInfo {
+                    hash: [1; 32],
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        utxo_service
+            .add_block(IndexedBlock {
+                height: 2,
+                utxos: vec![UTXO {
+                    vout: 1,
+                    ..utxo.clone()
+                }],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let (events, receiver) = broadcast::channel(16);
+        drop(events);
+        worker.run(receiver).await;
+        let pushed: Vec<_> = std::iter::from_fn(|| notifications.try_recv().ok()).collect();
+        assert_eq!(pushed.len(), 2);
+        assert!(matches!(
+            &pushed[0].notification,
+            ClientNotification::Invalidated { height: 0, outputs } if outputs[0].utxo == utxo
+        ));
+        assert!(matches!(
+            &pushed[1].notification,
+            ClientNotification::Found { height: 2, .. }
+        ));
+        let found = client_service.get_found_outputs(&client_id).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].height, 2);
+        assert_eq!(
+            client_service.scan_state().await.unwrap(),
+            Some(IndexState {
+                height: 2,
+                block_hash: [0; 32],
+            })
+        );
+
+        // Deleting the client deletes its found outputs
+        client_service.delete_client(&client_id).await.unwrap();
+        assert!(client_service
+            .get_found_outputs(&client_id)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    /// Fails the given number of scans, then scans like `LocalCompute`.
+    struct FlakyCompute {
+        failures: AtomicUsize,
+        compute: LocalCompute,
+    }
+
+    #[async_trait]
+    impl Compute for FlakyCompute {
+        async fn perform_ecdh(
+            &self,
+            utxos: &[UTXO],
+            receiver: &Receiver,
+            b_scan: &ScanSecret,
+        ) -> Result<Vec<MatchedOutput>> {
+            let failed = self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+            if failed.is_ok() {
+                return Err(Error::Compute("unavailable".to_string()));
+            }
+            self.compute.perform_ecdh(utxos, receiver, b_scan).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scan_worker_retries_failed_scans() {
+        let store = Arc::new(MemoryStore::new());
+        let utxo_service = Arc::new(UtxoService::new(store.clone(), 10));
+        let client_service = Arc::new(ClientService::new(store.clone()));
+        let worker = ScanWorker::new(
+            utxo_service.clone(),
+            client_service.clone(),
+            Arc::new(FlakyCompute {
+                failures: AtomicUsize::new(1),
+                compute: LocalCompute::new(),
+            }),
+        );
+        let client_id = client_service
+            .register_client(registration_request())
+            .await
+            .unwrap()
+            .client_id;
+        let utxo = paid_utxo();
+        for (height, vout) in [(1, 0), (2, 1)] {
+            utxo_service
+                .add_block(IndexedBlock {
+                    height,
+                    utxos: vec![UTXO {
+                        vout,
+                        ..utxo.clone()
+                    }],
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+        }
+
+        // The failed block is recorded while the scan moves on
+        let (events, receiver) = broadcast::channel(16);
+        events
+            .send(IndexEvent::BlockIndexed {
+                height: 1,
+                block_hash: [0; 32],
+            })
+            .unwrap();
+        drop(events);
+        worker.run(receiver).await;
+        assert_eq!(
+            client_service.failed_scans().await.unwrap(),
+            vec![(1, client_id.clone())]
+        );
+        let found = client_service.get_found_outputs(&client_id).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].height, 2);
+
+        // And scanned again on the next catch up
+        let (events, receiver) = broadcast::channel(16);
+        drop(events);
+        worker.run(receiver).await;
+        assert!(client_service.failed_scans().await.unwrap().is_empty());
+        let found = client_service.get_found_outputs(&client_id).await.unwrap();
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].height, 1);
+    }
+}
@@ -1,32 +1,307 @@
 // src/core/services/utxo_service.rs
-use crate::models::{TweakRequest, UTXO};
+use crate::indexer::IndexEvent;
+use crate::models::{
+    BlockFilter, BlockInfo, IndexedBlock, IndexedOutput, TweakRequest, TweakResponse,
+    UtxoWithSpend, UTXO,
+};
 use crate::storage::UtxoStore;
-use crate::Result;
+use crate::{Error, Result};
+use std::collections::HashSet;
 use std::sync::Arc;
+use tokio::sync::broadcast;
+
+/// Number of index events a slow subscriber can fall behind by.
+const INDEX_EVENT_CAPACITY: usize = 1024;
 
 pub struct UtxoService<S: UtxoStore + Send + Sync> {
     store: Arc<S>,
+    /// Maximum number of blocks a single range query may cover.
+    max_range_span: u64,
+    index_events: broadcast::Sender<IndexEvent>,
 }
 
 impl<S: UtxoStore + Send + Sync> UtxoService<S> {
-    pub fn new(store: Arc<S>) -> Self {
-        Self { store }
+    pub fn new(store: Arc<S>, max_range_span: u64) -> Self {
+        let (index_events, _) = broadcast::channel(INDEX_EVENT_CAPACITY);
+        Self {
+            store,
+            max_range_span,
+            index_events,
+        }
+    }
+
+    /// Sender for the indexers to publish their events on, see
+    /// `Indexer::with_events`.
+    pub fn index_events(&self) -> broadcast::Sender<IndexEvent> {
+        self.index_events.clone()
+    }
+
+    /// Receives the index events published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<IndexEvent> {
+        self.index_events.subscribe()
     }
 
     pub async fn add_utxo(&self, block_height: u64, utxo: UTXO) -> Result<()> {
         self.store.add_utxo(block_height, utxo).await
     }
 
+    /// Writes a whole block in one go, see `UtxoStore::add_block`.
+    pub async fn add_block(&self, block: IndexedBlock) -> Result<()> {
+        self.store.add_block(block).await
+    }
+
     pub async fn query_utxos(&self, block_height: u64) -> Result<Vec<UTXO>> {
         self.store.query_utxos(block_height).await
     }
 
-    pub async fn query_utxos_range(&self, request: &TweakRequest) -> Result<Vec<UTXO>> {
-        let mut all_utxos = Vec::new();
-        for height in request.start_height..=request.end_height {
-            let utxos = self.store.query_utxos(height).await?;
-            all_utxos.extend(utxos);
+    /// Returns the UTXOs of the blocks in the given height range that have
+    /// any, grouped by height.
+    pub async fn query_blocks_utxos(
+        &self,
+        start_height: u64,
+        end_height: u64,
+    ) -> Result<Vec<(u64, Vec<UTXO>)>> {
+        self.check_range(start_height, end_height)?;
+        self.store.query_utxos_range(start_height, end_height).await
+    }
+
+    /// Height of the last fully indexed block.
+    pub async fn tip_height(&self) -> Result<Option<u64>> {
+        Ok(self.store.index_state().await?.map(|state| state.height))
+    }
+
+    pub async fn get_block_info(&self, block_height: u64) -> Result<Option<BlockInfo>> {
+        self.store.get_block_info(block_height).await
+    }
+
+    pub fn max_range_span(&self) -> u64 {
+        self.max_range_span
+    }
+
+    /// Looks up an indexed output and its spend by outpoint.
+    pub async fn get_utxo(&self, txid: [u8; 32], vout: u32) -> Result<Option<IndexedOutput>> {
+        let Some((height, utxo)) = self.store.get_utxo(txid, vout).await? else {
+            return Ok(None);
+        };
+        let spent = self.store.get_spend(txid, vout).await?;
+        Ok(Some(IndexedOutput {
+            height,
+            output: UtxoWithSpend { utxo, spent },
+        }))
+    }
+
+    pub async fn query_utxos_range(
+        &self,
+        request: &TweakRequest,
+    ) -> Result<TweakResponse<UtxoWithSpend>> {
+        let all_utxos = self
+            .query_blocks_utxos(request.start_height, request.end_height)
+            .await?
+            .into_iter()
+            .flat_map(|(_, utxos)| utxos)
+            .collect();
+
+        let mut utxos = self.with_spends(all_utxos, false).await?;
+        let mut cut_through_omitted = 0;
+        if request.cut_through {
+            (utxos, cut_through_omitted) = cut_through(utxos);
+        }
+        if request.unspent_only {
+            utxos.retain(|utxo| utxo.spent.is_none());
+        }
+        Ok(TweakResponse {
+            utxos,
+            cut_through_omitted,
+        })
+    }
+
+    /// Returns the input tweak of each eligible transaction at
+    /// `block_height`, ordered by txid.
+    pub async fn block_tweaks(
+        &self,
+        block_height: u64,
+        cut_through: bool,
+    ) -> Result<Vec<[u8; 33]>> {
+        let mut utxos = self
+            .with_spends(self.query_utxos(block_height).await?, false)
+            .await?;
+        if cut_through {
+            utxos = self::cut_through(utxos).0;
+        }
+        // Stores are only required to group outputs by height
+        utxos.sort_by_key(|utxo| (utxo.utxo.txid, utxo.utxo.vout));
+        let mut txids = HashSet::new();
+        Ok(utxos
+            .into_iter()
+            .filter(|utxo| txids.insert(utxo.utxo.txid))
+            .map(|utxo| utxo.utxo.input_tweak)
+            .collect())
+    }
+
+    pub async fn get_filter(&self, block_height: u64) -> Result<Option<BlockFilter>> {
+        self.store.get_filter(block_height).await
+    }
+
+    /// Returns the filters of the indexed blocks in the given height range.
+    pub async fn get_filters(
+        &self,
+        start_height: u64,
+        end_height: u64,
+    ) -> Result<Vec<(u64, BlockFilter)>> {
+        self.check_range(start_height, end_height)?;
+        self.store.get_filters(start_height, end_height).await
+    }
+
+    fn check_range(&self, start_height: u64, end_height: u64) -> Result<()> {
+        if end_height < start_height {
+            return Err(Error::InvalidInput(format!(
+                "end height {} is below start height {}",
+                end_height, start_height
+            )));
+        }
+        let span = end_height - start_height + 1;
+        if span > self.max_range_span {
+            return Err(Error::InvalidInput(format!(
+                "range of {} blocks exceeds the maximum of {}",
+                span, self.max_range_span
+            )));
+        }
+        Ok(())
+    }
+
+    /// Looks up the spend of each UTXO, dropping the spent ones if
+    /// `unspent_only` is set.
+    pub async fn with_spends(
+        &self,
+        utxos: Vec<UTXO>,
+        unspent_only: bool,
+    ) -> Result<Vec<UtxoWithSpend>> {
+        let outpoints: Vec<_> = utxos.iter().map(|utxo| (utxo.txid, utxo.vout)).collect();
+        let spends = self.store.get_spends(&outpoints).await?;
+        Ok(utxos
+            .into_iter()
+            .zip(spends)
+            .filter(|(_, spent)| !unspent_only || spent.is_none())
+            .map(|(utxo, spent)| UtxoWithSpend { utxo, spent })
+            .collect())
+    }
+}
+
+/// Drops the outputs of transactions whose outputs have all been spent, and
+/// returns how many transactions, and so input tweaks, were dropped.
+fn cut_through(utxos: Vec<UtxoWithSpend>) -> (Vec<UtxoWithSpend>, usize) {
+    let mut txids = HashSet::new();
+    let mut unspent_txids = HashSet::new();
+    for utxo in &utxos {
+        txids.insert(utxo.utxo.txid);
+        if utxo.spent.is_none() {
+            unspent_txids.insert(utxo.utxo.txid);
+        }
+    }
+    let utxos = utxos
+        .into_iter()
+        .filter(|utxo| unspent_txids.contains(&utxo.utxo.txid))
+        .collect();
+    (utxos, txids.len() - unspent_txids.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::SpentInfo;
+    use crate::storage::MemoryStore;
+
+    fn utxo(txid: u8, vout: u32) -> UTXO {
+        UTXO {
+            txid: [txid; 32],
+            vout,
+            amount: 1000,
+            script_pubkey: [txid; 32],
+            input_tweak: [txid; 33],
         }
-        Ok(all_utxos)
+    }
+
+    fn tweak_request(cut_through: bool, unspent_only: bool) -> TweakRequest {
+        TweakRequest {
+            start_height: 1,
+            end_height: 2,
+            unspent_only,
+            with_spent_info: false,
+            cut_through,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_utxos_range_cut_through() {
+        let service = UtxoService::new(Arc::new(MemoryStore::new()), 10);
+        let spend = SpentInfo {
+            height: 3,
+            txid: [9; 32],
+        };
+        // Transaction 1 is fully spent, transaction 2 partially, 3 not at all
+        service.add_utxo(1, utxo(1, 0)).await.unwrap();
+        service.add_utxo(1, utxo(1, 1)).await.unwrap();
+        service.add_utxo(1, utxo(2, 0)).await.unwrap();
+        service.add_utxo(2, utxo(2, 1)).await.unwrap();
+        service.add_utxo(2, utxo(3, 0)).await.unwrap();
+        service
+            .add_block(IndexedBlock {
+                height: 3,
+                spends: [(1, 0), (1, 1), (2, 0)]
+                    .into_iter()
+                    .map(|(txid, vout)| ([txid; 32], vout, spend))
+                    .collect(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let response = service
+            .query_utxos_range(&tweak_request(false, false))
+            .await
+            .unwrap();
+        assert_eq!(response.utxos.len(), 5);
+        assert_eq!(response.cut_through_omitted, 0);
+
+        let response = service
+            .query_utxos_range(&tweak_request(true, false))
+            .await
+            .unwrap();
+        let outpoints: Vec<_> = response
+            .utxos
+            .iter()
+            .map(|u| (u.utxo.txid[0], u.utxo.vout))
+            .collect();
+        assert_eq!(outpoints, vec![(2, 0), (2, 1), (3, 0)]);
+        assert_eq!(response.cut_through_omitted, 1);
+
+        let response = service
+            .query_utxos_range(&tweak_request(true, true))
+            .await
+            .unwrap();
+        assert_eq!(response.utxos.len(), 2);
+        assert_eq!(response.cut_through_omitted, 1);
+
+        // One tweak per transaction
+        assert_eq!(
+            service.block_tweaks(1, false).await.unwrap(),
+            vec![[1; 33], [2; 33]]
+        );
+        assert_eq!(service.block_tweaks(1, true).await.unwrap(), vec![[2; 33]]);
+        assert!(service.block_tweaks(4, false).await.unwrap().is_empty());
+
+        // Ranges longer than the maximum span are rejected
+        let request = TweakRequest {
+            end_height: 11,
+            ..tweak_request(false, false)
+        };
+        assert!(matches!(
+            service.query_utxos_range(&request).await,
+            Err(Error::InvalidInput(_))
+        ));
+        assert!(matches!(
+            service.get_filters(2, 1).await,
+            Err(Error::InvalidInput(_))
+        ));
     }
 }
@@ -0,0 +1,403 @@
+use crate::models::WebhookDelivery;
+use crate::services::ClientService;
+use crate::storage::ClientStore;
+use crate::Result;
+use futures_util::{stream, StreamExt};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::error::RecvError;
+
+/// Longest wait between two attempts of a delivery.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(3600);
+/// Shortest wait between two checks of the queue.
+const MIN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Most deliveries in flight at once.
+const MAX_CONCURRENT_DELIVERIES: usize = 16;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// POSTs the deliveries queued by `ClientService::notify` to the clients'
+/// webhooks. Failed deliveries are retried with exponential backoff and
+/// dropped after `max_attempts`. The queue is persisted, so deliveries
+/// pending at shutdown are sent after a restart.
+///
+/// Webhooks are only delivered to publicly routable addresses, checked on
+/// every connection, unless their host is explicitly allowed.
+pub struct WebhookWorker<S: ClientStore + Send + Sync> {
+    client_service: Arc<ClientService<S>>,
+    http: reqwest::Client,
+    allowed_hosts: Arc<HashSet<String>>,
+    max_attempts: u32,
+    retry_delay: Duration,
+}
+
+impl<S: ClientStore + Send + Sync> WebhookWorker<S> {
+    pub fn new(
+        client_service: Arc<ClientService<S>>,
+        max_attempts: u32,
+        retry_delay: Duration,
+    ) -> Self {
+        let allowed_hosts = Arc::new(HashSet::new());
+        Self {
+            client_service,
+            http: http_client(allowed_hosts.clone()),
+            allowed_hosts,
+            max_attempts,
+            retry_delay,
+        }
+    }
+
+    /// Allows delivering webhooks to `hosts`, by name or IP address, even
+    /// if they are not publicly routable.
+    pub fn with_allowed_hosts(mut self, hosts: Vec<String>) -> Self {
+        self.allowed_hosts = Arc::new(hosts.into_iter().collect());
+        self.http = http_client(self.allowed_hosts.clone());
+        self
+    }
+
+    /// Delivers queued webhooks as they come due, checking again every
+    /// `poll_interval`, of at least a second, and whenever a client
+    /// notification is sent.
+    pub async fn run(&self, poll_interval: Duration) {
+        let poll_interval = poll_interval.max(MIN_POLL_INTERVAL);
+        let mut notifications = self.client_service.subscribe();
+        loop {
+            if let Err(e) = self.deliver_due().await {
+                log::error!("webhook delivery failed: {}", e);
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(poll_interval) => {}
+                event = notifications.recv() => {
+                    if let Err(RecvError::Closed) = event {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Attempts every queued delivery that is due, up to
+    /// `MAX_CONCURRENT_DELIVERIES` at once, returning the number delivered.
+    pub async fn deliver_due(&self) -> Result<usize> {
+        let now = unix_time();
+        let due = self
+            .client_service
+            .pending_webhooks()
+            .await?
+            .into_iter()
+            .filter(|delivery| delivery.next_attempt <= now);
+        let mut results = stream::iter(due)
+            .map(|delivery| async move {
+                let result = self.send(&delivery).await;
+                (delivery, result)
+            })
+            .buffer_unordered(MAX_CONCURRENT_DELIVERIES);
+        let mut delivered = 0;
+        while let Some((mut delivery, result)) = results.next().await {
+            match result {
+                Ok(()) => {
+                    self.client_service.remove_webhook(&delivery.id).await?;
+                    delivered += 1;
+                }
+                Err(e) => {
+                    delivery.attempts += 1;
+                    if delivery.attempts >= self.max_attempts {
+                        log::error!(
+                            "dropping webhook {} for client {} after {} attempts: {}",
+                            delivery.id,
+                            delivery.client_id,
+                            delivery.attempts,
+                            e
+                        );
+                        self.client_service.remove_webhook(&delivery.id).await?;
+                        continue;
+                    }
+                    log::warn!("webhook {} failed, retrying: {}", delivery.id, e);
+                    delivery.next_attempt = now + self.backoff(delivery.attempts).as_secs();
+                    self.client_service.requeue_webhook(delivery).await?;
+                }
+            }
+        }
+        Ok(delivered)
+    }
+
+    /// Wait before the attempt following the `attempts`th failure.
+    fn backoff(&self, attempts: u32) -> Duration {
+        self.retry_delay
+            .saturating_mul(2u32.saturating_pow(attempts - 1))
+            .min(MAX_RETRY_DELAY)
+    }
+
+    async fn send(&self, delivery: &WebhookDelivery) -> std::result::Result<(), BoxError> {
+        // Addresses given in the URL are not looked up, so check them here
+        let url = reqwest::Url::parse(&delivery.url)?;
+        let host = url
+            .host_str()
+            .unwrap_or_default()
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            if !self.allowed_hosts.contains(host) && !is_public(ip) {
+                return Err(format!("{} is not a public address", ip).into());
+            }
+        }
+        self.http
+            .post(&delivery.url)
+            .header("content-type", "application/json")
+            .header("X-Deafen-Delivery", &delivery.id)
+            .header(
+                "X-Deafen-Signature",
+                format!("sha256={}", delivery.signature),
+            )
+            .body(delivery.body.clone())
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+fn http_client(allowed_hosts: Arc<HashSet<String>>) -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        // A redirect or a proxy could lead anywhere, including to a private
+        // address
+        .redirect(reqwest::redirect::Policy::none())
+        .no_proxy()
+        .dns_resolver(Arc::new(PublicResolver { allowed_hosts }))
+        .build()
+        .expect("default TLS backend is available")
+}
+
+/// Resolves webhook hosts to their publicly routable addresses only, unless
+/// the host is allowed. Checking at connection time rather than when the
+/// webhook is registered keeps a host from being pointed at a private
+/// address later.
+struct PublicResolver {
+    allowed_hosts: Arc<HashSet<String>>,
+}
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        let allowed = self.allowed_hosts.contains(&host);
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| allowed || is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", host).into());
+            }
+            Ok::<Addrs, BoxError>(Box::new(addrs.into_iter()))
+        })
+    }
+}
+
+/// Whether `ip` is routable on the public internet.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped().or_else(|| embedded_v4(ip)) {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+/// The IPv4 address a NAT64, 6to4 or IPv4-compatible address routes to.
+fn embedded_v4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    let o = ip.octets();
+    match segments {
+        [0x64, 0xff9b, 0, 0, 0, 0, _, _] | [0, 0, 0, 0, 0, 0, _, _] => {
+            Some(Ipv4Addr::new(o[12], o[13], o[14], o[15]))
+        }
+        [0x2002, ..] => Some(Ipv4Addr::new(o[2], o[3], o[4], o[5])),
+        _ => None,
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // "This network", shared address space, IETF protocol
+        // assignments, benchmarking and reserved ranges
+        || a == 0
+        || (a == 100 && (b & 0xc0) == 64)
+        || (a == 192 && b == 0 && c == 0)
+        || (a == 198 && (b & 0xfe) == 18)
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local, link-local and documentation ranges
+        || (segments[0] & 0xfe00) == 0xfc00
+        || (segments[0] & 0xffc0) == 0xfe80
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8))
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{
+        ClientEvent, ClientNotification, RegistrationRequest, WebhookPayload, WebhookSecret,
+    };
+    use crate::services::client_service::webhook_signature;
+    use crate::services::tests::registration_request;
+    use crate::storage::MemoryStore;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use warp::http::StatusCode;
+    use warp::Filter;
+
+    #[tokio::test]
+    async fn test_webhook_delivery() {
+        // A receiver that fails its first request
+        let requests = Arc::new(AtomicUsize::new(0));
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let route = {
+            let requests = requests.clone();
+            let received = received.clone();
+            warp::post()
+                .and(warp::path("hook"))
+                .and(warp::header::<String>("X-Deafen-Signature"))
+                .and(warp::body::bytes())
+                .map(move |signature: String, body: warp::hyper::body::Bytes| {
+                    if requests.fetch_add(1, Ordering::SeqCst) == 0 {
+                        return StatusCode::SERVICE_UNAVAILABLE;
+                    }
+                    let body = String::from_utf8(body.to_vec()).unwrap();
+                    received.lock().unwrap().push((signature, body));
+                    StatusCode::OK
+                })
+        };
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let store = Arc::new(MemoryStore::new());
+        let client_service = Arc::new(ClientService::new(store));
+        let registration = client_service
+            .register_client(RegistrationRequest {
+                webhook_url: Some(format!("http://{}/hook", addr)),
+                ..registration_request()
+            })
+            .await
+            .unwrap();
+        let secret: [u8; 32] = hex::decode(registration.webhook_secret.unwrap())
+            .unwrap()
+            .try_into()
+            .unwrap();
+
+        let notification = ClientNotification::Found {
+            height: 1,
+            outputs: vec![],
+        };
+        client_service
+            .notify(ClientEvent {
+                client_id: registration.client_id.clone(),
+                notification: notification.clone(),
+            })
+            .await
+            .unwrap();
+
+        // Loopback is refused unless allowed
+        let worker = WebhookWorker::new(client_service.clone(), 3, Duration::ZERO);
+        assert_eq!(worker.deliver_due().await.unwrap(), 0);
+        assert_eq!(requests.load(Ordering::SeqCst), 0);
+
+        // The first request fails and is retried without delay
+        let worker = WebhookWorker::new(client_service.clone(), 3, Duration::ZERO)
+            .with_allowed_hosts(vec!["127.0.0.1".to_string()]);
+        assert_eq!(worker.deliver_due().await.unwrap(), 0);
+        let pending = client_service.pending_webhooks().await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].attempts, 2);
+        assert_eq!(worker.deliver_due().await.unwrap(), 1);
+        assert!(client_service.pending_webhooks().await.unwrap().is_empty());
+
+        // The body is signed with the secret issued at registration
+        let received = received.lock().unwrap().clone();
+        assert_eq!(received.len(), 1);
+        let (signature, body) = &received[0];
+        assert_eq!(
+            *signature,
+            format!(
+                "sha256={}",
+                webhook_signature(&WebhookSecret::from(secret), body)
+            )
+        );
+        let payload: WebhookPayload = serde_json::from_str(body).unwrap();
+        assert_eq!(payload.client_id, registration.client_id);
+        assert_eq!(payload.delivery_id, pending[0].id);
+        assert_eq!(payload.notification, notification);
+
+        // Deliveries are dropped once out of attempts
+        let worker = WebhookWorker::new(client_service.clone(), 1, Duration::ZERO)
+            .with_allowed_hosts(vec!["127.0.0.1".to_string()]);
+        client_service
+            .requeue_webhook(WebhookDelivery {
+                url: "http://127.0.0.1:1/hook".to_string(),
+                ..pending[0].clone()
+            })
+            .await
+            .unwrap();
+        assert_eq!(worker.deliver_due().await.unwrap(), 0);
+        assert!(client_service.pending_webhooks().await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_is_public() {
+        for ip in [
+            "1.1.1.1",
+            "2606:4700:4700::1111",
+            "64:ff9b::101:101",
+            "2002:101:101::1",
+            "::1.1.1.1",
+        ] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "0.0.0.0",
+            "127.0.0.1",
+            "10.0.0.1",
+            "100.64.0.1",
+            "169.254.169.254",
+            "172.16.0.1",
+            "192.168.1.1",
+            "255.255.255.255",
+            "::1",
+            "::ffff:127.0.0.1",
+            "fd00::1",
+            "fe80::1",
+            "64:ff9b::7f00:1",
+            "64:ff9b::a9fe:a9fe",
+            "2002:a00:1::1",
+            "2002:c0a8:101::1",
+            "::127.0.0.1",
+            "::10.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+}
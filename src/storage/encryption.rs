@@ -0,0 +1,173 @@
+use crate::{Error, Result};
+use bitcoin::hashes::{hmac, sha256, Hash, HashEngine};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::RngCore;
+use std::path::Path;
+use zeroize::Zeroize;
+
+const NONCE_LEN: usize = 12;
+
+/// Prefix of the messages `MasterKey::tag` authenticates, so tags never
+/// collide with another use of the key.
+const TAG_DOMAIN: &[u8] = b"deafen/tag";
+
+/// Server key used to encrypt client records at rest with
+/// ChaCha20-Poly1305. It is zeroed on drop and cannot be copied.
+pub struct MasterKey([u8; 32]);
+
+impl MasterKey {
+    pub fn generate() -> Self {
+        let mut key = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        Self(key)
+    }
+
+    /// Reads a hex encoded 32-byte key from `path`, first writing a new
+    /// random key there if the file does not exist.
+    pub fn load_or_create(path: &Path) -> Result<Self> {
+        match path.exists() {
+            true => Self::load(path),
+            false => Self::create(path),
+        }
+    }
+
+    /// Writes a new random key to `path`, failing if the file exists.
+    pub fn create(path: &Path) -> Result<Self> {
+        let key = Self::generate();
+        let mut contents = hex::encode(key.0);
+        let written = write_key_file(path, &contents);
+        contents.zeroize();
+        written?;
+        Ok(key)
+    }
+
+    /// Reads a hex encoded 32-byte key from `path`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut contents = std::fs::read_to_string(path).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => {
+                Error::InvalidInput(format!("master key file {} not found", path.display()))
+            }
+            _ => Error::InvalidInput(format!("failed to read {}: {}", path.display(), e)),
+        })?;
+        let mut key = Self([0u8; 32]);
+        let decoded = hex::decode_to_slice(contents.trim(), &mut key.0);
+        contents.zeroize();
+        decoded.map_err(|_| {
+            Error::InvalidInput(format!(
+                "{} must hold a hex encoded 32-byte key",
+                path.display()
+            ))
+        })?;
+        Ok(key)
+    }
+
+    /// Encrypts `plaintext`, binding it to `aad`. The random nonce is
+    /// prepended to the ciphertext.
+    pub fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&self.0))
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .expect("encryption cannot fail for in-memory buffers");
+        [&nonce[..], &ciphertext].concat()
+    }
+
+    /// Keyed hash of `data`, for looking up records by a value that must not
+    /// be stored in the clear. Tags change when the key is rotated.
+    pub fn tag(&self, data: &[u8]) -> [u8; 32] {
+        let mut engine = hmac::HmacEngine::<sha256::Hash>::new(&self.0);
+        engine.input(TAG_DOMAIN);
+        engine.input(data);
+        hmac::Hmac::<sha256::Hash>::from_engine(engine).to_byte_array()
+    }
+
+    /// Decrypts a record produced by `seal` with the same `aad`. Fails with
+    /// `Error::WrongMasterKey` if it was sealed under another key.
+    pub fn open(&self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return Err(Error::WrongMasterKey);
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        ChaCha20Poly1305::new(Key::from_slice(&self.0))
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| Error::WrongMasterKey)
+    }
+}
+
+impl Drop for MasterKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+#[cfg(unix)]
+fn write_key_file(path: &Path, contents: &str) -> Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .map_err(|e| Error::InvalidInput(format!("failed to write {}: {}", path.display(), e)))
+}
+
+#[cfg(not(unix))]
+fn write_key_file(path: &Path, contents: &str) -> Result<()> {
+    std::fs::write(path, contents)
+        .map_err(|e| Error::InvalidInput(format!("failed to write {}: {}", path.display(), e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_and_open() {
+        let key = MasterKey::generate();
+        let sealed = key.seal(b"scan key", b"client");
+        assert_eq!(key.open(&sealed, b"client").unwrap(), b"scan key");
+
+        // Another key or another client id cannot open the record
+        assert!(matches!(
+            MasterKey::generate().open(&sealed, b"client"),
+            Err(Error::WrongMasterKey)
+        ));
+        assert!(matches!(
+            key.open(&sealed, b"other"),
+            Err(Error::WrongMasterKey)
+        ));
+
+        // Tags depend on the key and the data only
+        assert_eq!(key.tag(b"outpoint"), key.tag(b"outpoint"));
+        assert_ne!(key.tag(b"outpoint"), key.tag(b"other"));
+        assert_ne!(key.tag(b"outpoint"), MasterKey::generate().tag(b"outpoint"));
+
+        // Keys are persisted to and read back from the key file
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("master.key");
+        let created = MasterKey::load_or_create(&path).unwrap();
+        let loaded = MasterKey::load_or_create(&path).unwrap();
+        assert_eq!(loaded.open(&created.seal(b"x", b""), b"").unwrap(), b"x");
+        assert!(MasterKey::create(&path).is_err());
+        assert!(matches!(
+            MasterKey::load(&dir.path().join("missing.key")),
+            Err(Error::InvalidInput(_))
+        ));
+    }
+}
@@ -1,13 +1,20 @@
-use crate::models::{ClientData, UTXO};
-use crate::storage::{ClientStore, UtxoStore};
+use crate::models::{
+    BlockFilter, BlockInfo, ClientData, ClientInfo, FoundOutput, IndexState, IndexedBlock,
+    MatchedOutput, ScanSecret, SpentInfo, WebhookDelivery, UTXO,
+};
+use crate::storage::{ClientStore, MasterKey, UtxoStore};
 use crate::{Error, Result};
 use async_trait::async_trait;
 use libmdbx::orm::{
     table, table_info, Database as OrmDatabase, DatabaseChart, Decodable, Encodable,
 };
 use once_cell::sync::Lazy;
-use std::path::PathBuf;
-use std::sync::Arc;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use silentpayments::receiving::Receiver;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use zeroize::Zeroize;
 
 impl Encodable for UTXO {
     type Encoded = Vec<u8>;
@@ -23,7 +30,35 @@ impl Decodable for UTXO {
     }
 }
 
-impl Encodable for ClientData {
+impl Encodable for BlockInfo {
+    type Encoded = Vec<u8>;
+
+    fn encode(self) -> Self::Encoded {
+        bincode::serialize(&self).unwrap()
+    }
+}
+
+impl Decodable for BlockInfo {
+    fn decode(v: &[u8]) -> std::result::Result<Self, anyhow::Error> {
+        Ok(bincode::deserialize(v)?)
+    }
+}
+
+impl Encodable for BlockFilter {
+    type Encoded = Vec<u8>;
+
+    fn encode(self) -> Self::Encoded {
+        bincode::serialize(&self).unwrap()
+    }
+}
+
+impl Decodable for BlockFilter {
+    fn decode(v: &[u8]) -> std::result::Result<Self, anyhow::Error> {
+        Ok(bincode::deserialize(v)?)
+    }
+}
+
+impl Encodable for SpentInfo {
     type Encoded = Vec<u8>;
 
     fn encode(self) -> Self::Encoded {
@@ -31,44 +66,584 @@ impl Encodable for ClientData {
     }
 }
 
-impl Decodable for ClientData {
+impl Decodable for SpentInfo {
     fn decode(v: &[u8]) -> std::result::Result<Self, anyhow::Error> {
         Ok(bincode::deserialize(v)?)
     }
 }
 
+impl Encodable for IndexState {
+    type Encoded = Vec<u8>;
+
+    fn encode(self) -> Self::Encoded {
+        bincode::serialize(&self).unwrap()
+    }
+}
+
+impl Decodable for IndexState {
+    fn decode(v: &[u8]) -> std::result::Result<Self, anyhow::Error> {
+        Ok(bincode::deserialize(v)?)
+    }
+}
+
+/// Ids of the clients a block could not be scanned for.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ClientIds(Vec<String>);
+
+impl Encodable for ClientIds {
+    type Encoded = Vec<u8>;
+
+    fn encode(self) -> Self::Encoded {
+        bincode::serialize(&self).unwrap()
+    }
+}
+
+impl Decodable for ClientIds {
+    fn decode(v: &[u8]) -> std::result::Result<Self, anyhow::Error> {
+        Ok(bincode::deserialize(v)?)
+    }
+}
+
+/// A record sealed under the master key, see `MasterKey::seal`.
+#[derive(Clone, Debug)]
+pub struct EncryptedRecord(Vec<u8>);
+
+/// Version byte prepended to a sealed client record, so the layout of
+/// `ClientData` can change without breaking records already stored.
+const CLIENT_DATA_VERSION: u8 = 1;
+
+/// Version of client records written before they were encrypted.
+const LEGACY_CLIENT_DATA_VERSION: u8 = 0;
+
+/// Layout of `ClientData` in records of `LEGACY_CLIENT_DATA_VERSION`.
+#[derive(Deserialize)]
+struct LegacyClientData {
+    receiver: Receiver,
+    b_scan: ScanSecret,
+}
+
+fn encode_client_data(client_data: &ClientData) -> Result<Vec<u8>> {
+    let mut payload = vec![CLIENT_DATA_VERSION];
+    bincode::serialize_into(&mut payload, client_data).map_err(anyhow::Error::from)?;
+    Ok(payload)
+}
+
+/// Decodes a client record of any known version. Fields missing from older
+/// layouts are left at their defaults, so a legacy client has no API token
+/// and no stored registration details until it registers again.
+fn decode_client_data(payload: &[u8]) -> Result<ClientData> {
+    match payload.split_first() {
+        Some((&CLIENT_DATA_VERSION, data)) => {
+            Ok(bincode::deserialize(data).map_err(anyhow::Error::from)?)
+        }
+        Some((&LEGACY_CLIENT_DATA_VERSION, data)) => {
+            let legacy: LegacyClientData =
+                bincode::deserialize(data).map_err(anyhow::Error::from)?;
+            Ok(ClientData {
+                receiver: legacy.receiver,
+                b_scan: Arc::new(legacy.b_scan),
+                info: ClientInfo::default(),
+                token_hash: [0; 32],
+                last_scanned_height: None,
+                webhook_secret: None,
+            })
+        }
+        Some((version, _)) => {
+            Err(anyhow::anyhow!("unknown client record version {}", version).into())
+        }
+        None => Err(anyhow::anyhow!("empty client record").into()),
+    }
+}
+
+impl Encodable for EncryptedRecord {
+    type Encoded = Vec<u8>;
+
+    fn encode(self) -> Self::Encoded {
+        self.0
+    }
+}
+
+impl Decodable for EncryptedRecord {
+    fn decode(v: &[u8]) -> std::result::Result<Self, anyhow::Error> {
+        Ok(EncryptedRecord(v.to_vec()))
+    }
+}
+
+/// Key of the `UTXOs` table: the block height followed by the outpoint.
+///
+/// Heights are encoded big-endian so that all outputs of a block are stored
+/// contiguously and blocks are ordered by height.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UtxoKey {
+    pub height: u64,
+    pub txid: [u8; 32],
+    pub vout: u32,
+}
+
+impl UtxoKey {
+    const LEN: usize = 8 + 32 + 4;
+
+    fn new(height: u64, utxo: &UTXO) -> Self {
+        UtxoKey {
+            height,
+            txid: utxo.txid,
+            vout: utxo.vout,
+        }
+    }
+
+    /// Smallest key at `height`, used to position a cursor on a block.
+    fn block_start(height: u64) -> Self {
+        UtxoKey {
+            height,
+            txid: [0; 32],
+            vout: 0,
+        }
+    }
+}
+
+impl Encodable for UtxoKey {
+    type Encoded = [u8; UtxoKey::LEN];
+
+    fn encode(self) -> Self::Encoded {
+        let mut buf = [0u8; UtxoKey::LEN];
+        buf[..8].copy_from_slice(&self.height.to_be_bytes());
+        buf[8..40].copy_from_slice(&self.txid);
+        buf[40..].copy_from_slice(&self.vout.to_be_bytes());
+        buf
+    }
+}
+
+impl Decodable for UtxoKey {
+    fn decode(v: &[u8]) -> std::result::Result<Self, anyhow::Error> {
+        if v.len() != UtxoKey::LEN {
+            anyhow::bail!("invalid UTXO key length: {}", v.len());
+        }
+        Ok(UtxoKey {
+            height: u64::from_be_bytes(v[..8].try_into()?),
+            txid: v[8..40].try_into()?,
+            vout: u32::from_be_bytes(v[40..].try_into()?),
+        })
+    }
+}
+
+/// Key of the tables indexed by outpoint: the txid followed by the
+/// big-endian output index.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutPointKey {
+    pub txid: [u8; 32],
+    pub vout: u32,
+}
+
+impl OutPointKey {
+    const LEN: usize = 32 + 4;
+}
+
+impl Encodable for OutPointKey {
+    type Encoded = [u8; OutPointKey::LEN];
+
+    fn encode(self) -> Self::Encoded {
+        let mut buf = [0u8; OutPointKey::LEN];
+        buf[..32].copy_from_slice(&self.txid);
+        buf[32..].copy_from_slice(&self.vout.to_be_bytes());
+        buf
+    }
+}
+
+impl Decodable for OutPointKey {
+    fn decode(v: &[u8]) -> std::result::Result<Self, anyhow::Error> {
+        if v.len() != OutPointKey::LEN {
+            anyhow::bail!("invalid outpoint key length: {}", v.len());
+        }
+        Ok(OutPointKey {
+            txid: v[..32].try_into()?,
+            vout: u32::from_be_bytes(v[32..].try_into()?),
+        })
+    }
+}
+
+/// Key of the `FoundOutputs` table: the length-prefixed client id, the
+/// big-endian height and a tag of the outpoint, so that each client's outputs
+/// are stored contiguously in height order without the outpoints being
+/// readable from the keys. Within a height the tags are in no useful order,
+/// so reads sort each height by outpoint.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FoundOutputKey {
+    pub client_id: String,
+    pub height: u64,
+    /// `MasterKey::tag` of the outpoint.
+    pub outpoint_tag: [u8; 32],
+}
+
+impl FoundOutputKey {
+    const SUFFIX_LEN: usize = 8 + 32;
+
+    fn new(master_key: &MasterKey, client_id: &str, height: u64, utxo: &UTXO) -> Result<Self> {
+        Ok(FoundOutputKey {
+            outpoint_tag: master_key.tag(&outpoint_key(utxo).encode()),
+            ..Self::height_start(client_id, height)?
+        })
+    }
+
+    /// Smallest key of `client_id`, used to position a cursor on a client.
+    fn client_start(client_id: &str) -> Result<Self> {
+        Self::height_start(client_id, 0)
+    }
+
+    /// Smallest key of `client_id` at `height`. Fails for client ids too long
+    /// for the one byte length prefix.
+    fn height_start(client_id: &str, height: u64) -> Result<Self> {
+        if client_id.len() > u8::MAX as usize {
+            return Err(Error::InvalidInput(format!(
+                "client id longer than {} bytes",
+                u8::MAX
+            )));
+        }
+        Ok(FoundOutputKey {
+            client_id: client_id.to_string(),
+            height,
+            outpoint_tag: [0; 32],
+        })
+    }
+}
+
+impl Encodable for FoundOutputKey {
+    type Encoded = Vec<u8>;
+
+    fn encode(self) -> Self::Encoded {
+        let client_id = self.client_id.as_bytes();
+        let len = u8::try_from(client_id.len()).expect("client id length is checked on creation");
+        let mut buf = Vec::with_capacity(1 + client_id.len() + Self::SUFFIX_LEN);
+        buf.push(len);
+        buf.extend_from_slice(client_id);
+        buf.extend_from_slice(&self.height.to_be_bytes());
+        buf.extend_from_slice(&self.outpoint_tag);
+        buf
+    }
+}
+
+impl Decodable for FoundOutputKey {
+    fn decode(v: &[u8]) -> std::result::Result<Self, anyhow::Error> {
+        let Some((&len, rest)) = v.split_first() else {
+            anyhow::bail!("empty found output key");
+        };
+        if rest.len() != len as usize + Self::SUFFIX_LEN {
+            anyhow::bail!("invalid found output key length: {}", v.len());
+        }
+        let (client_id, suffix) = rest.split_at(len as usize);
+        Ok(FoundOutputKey {
+            client_id: String::from_utf8(client_id.to_vec())?,
+            height: u64::from_be_bytes(suffix[..8].try_into()?),
+            outpoint_tag: suffix[8..].try_into()?,
+        })
+    }
+}
+
+/// Order of a client's found outputs: by height, then txid and vout.
+fn found_output_order(output: &FoundOutput) -> (u64, [u8; 32], u32) {
+    (output.height, output.utxo.txid, output.utxo.vout)
+}
+
+/// Moves the outputs of one height to a page in order, leaving out those up
+/// to `after`.
+fn push_page_outputs(
+    page: &mut Vec<FoundOutput>,
+    height_outputs: &mut Vec<FoundOutput>,
+    after: Option<(u64, [u8; 32], u32)>,
+) {
+    height_outputs.sort_by_key(found_output_order);
+    page.extend(
+        height_outputs
+            .drain(..)
+            .filter(|output| after.map_or(true, |after| found_output_order(output) > after)),
+    );
+}
+
+fn outpoint_key(utxo: &UTXO) -> OutPointKey {
+    OutPointKey {
+        txid: utxo.txid,
+        vout: utxo.vout,
+    }
+}
+
+table!(
+    /// Table for UTXOs, keyed by block height and outpoint.
+    ( UTXOs ) UtxoKey => UTXO
+);
+
+table!(
+    /// Table for the hash and parent hash of each indexed block.
+    ( Blocks ) u64 => BlockInfo
+);
+
+table!(
+    /// Table for the compact filter of each indexed block.
+    ( Filters ) u64 => BlockFilter
+);
+
+table!(
+    /// Table for the spends of taproot outputs, keyed by the spent outpoint.
+    ( Spends ) OutPointKey => SpentInfo
+);
+
+table!(
+    /// Table for the same spends keyed by spending height, so they can be
+    /// rolled back per block.
+    ( SpendsByHeight ) UtxoKey => SpentInfo
+);
+
 table!(
-    /// Table for UTXOs.
-    ( UTXOs ) u64 => UTXO
+    /// Table for the height of each indexed output, keyed by outpoint.
+    ( OutPoints ) OutPointKey => u64
 );
 
 table!(
-    /// Table for Client keys.
-    ( Clients ) String => ClientData
+    /// Table holding the index state under `INDEX_STATE_KEY` and the
+    /// background scanner's position under `SCAN_STATE_KEY`.
+    ( IndexStates ) String => IndexState
 );
 
+const INDEX_STATE_KEY: &str = "indexer";
+const SCAN_STATE_KEY: &str = "scanner";
+
+table!(
+    /// Table for Client keys, encrypted under the master key with the client
+    /// id as associated data.
+    ( Clients ) String => EncryptedRecord
+);
+
+table!(
+    /// Table holding a known value sealed under the master key, so a wrong
+    /// key is caught when the database is opened.
+    ( KeyCheck ) String => EncryptedRecord
+);
+
+table!(
+    /// Table for the outputs found for each client by the background
+    /// scanner, keyed by client, height and outpoint. Each `FoundOutput` is
+    /// sealed under the master key.
+    ( FoundOutputs ) FoundOutputKey => EncryptedRecord
+);
+
+table!(
+    /// Table for the webhook delivery queue, keyed by delivery id, so queued
+    /// deliveries survive restarts. Each `WebhookDelivery` is sealed under
+    /// the master key.
+    ( WebhookDeliveries ) String => EncryptedRecord
+);
+
+table!(
+    /// Table for the clients whose scan of a block failed, keyed by height.
+    ( FailedScans ) u64 => ClientIds
+);
+
+const KEY_CHECK_KEY: &str = "master_key";
+const KEY_CHECK_VALUE: &[u8] = b"deafen";
+
 static TABLES: Lazy<DatabaseChart> = Lazy::new(|| {
-    [table_info!(UTXOs), table_info!(Clients)]
-        .into_iter()
-        .collect()
+    [
+        table_info!(UTXOs),
+        table_info!(Blocks),
+        table_info!(Filters),
+        table_info!(Spends),
+        table_info!(SpendsByHeight),
+        table_info!(OutPoints),
+        table_info!(IndexStates),
+        table_info!(Clients),
+        table_info!(KeyCheck),
+        table_info!(FoundOutputs),
+        table_info!(WebhookDeliveries),
+        table_info!(FailedScans),
+    ]
+    .into_iter()
+    .collect()
 });
 
 pub struct MdbxDatabase {
     db: Arc<OrmDatabase>,
+    master_key: RwLock<MasterKey>,
 }
 
 impl MdbxDatabase {
-    pub fn new(path: PathBuf) -> Result<Self> {
+    /// Opens the database, failing with `Error::WrongMasterKey` if it was
+    /// created with another master key.
+    ///
+    /// A database written before client records were encrypted has no key
+    /// check yet. Its plaintext client records are sealed under `master_key`
+    /// in the same transaction that adds the check.
+    pub fn new(path: PathBuf, master_key: MasterKey) -> Result<Self> {
+        Self::with_master_key(
+            Arc::new(OrmDatabase::create(Some(path), &TABLES)?),
+            master_key,
+        )
+    }
+
+    /// Opens the database with the key in `master_key_file`. A new key is
+    /// only written there while no client is registered, so a lost key file
+    /// is reported instead of silently replaced.
+    pub fn open(path: PathBuf, master_key_file: &Path) -> Result<Self> {
         let db = Arc::new(OrmDatabase::create(Some(path), &TABLES)?);
-        Ok(MdbxDatabase { db })
+        let master_key = if master_key_file.exists() {
+            MasterKey::load(master_key_file)?
+        } else {
+            let tx = db.begin_readwrite()?;
+            if tx.cursor::<Clients>()?.walk(None).next().is_some() {
+                return Err(Error::InvalidInput(format!(
+                    "master key file {} not found, it is needed to read the registered clients",
+                    master_key_file.display()
+                )));
+            }
+            // Nothing is sealed under the old key, so the new one replaces it
+            tx.del::<KeyCheck>(KEY_CHECK_KEY.to_string(), None)?;
+            tx.commit()?;
+            MasterKey::create(master_key_file)?
+        };
+        Self::with_master_key(db, master_key)
+    }
+
+    fn with_master_key(db: Arc<OrmDatabase>, master_key: MasterKey) -> Result<Self> {
+        let tx = db.begin_readwrite()?;
+        match tx.get::<KeyCheck>(KEY_CHECK_KEY.to_string())? {
+            Some(check) => {
+                master_key.open(&check.0, KEY_CHECK_KEY.as_bytes())?;
+            }
+            None => {
+                let legacy_records = tx
+                    .cursor::<Clients>()?
+                    .walk(None)
+                    .collect::<std::result::Result<Vec<_>, _>>()?;
+                if !legacy_records.is_empty() {
+                    log::info!(
+                        "encrypting {} plaintext client records",
+                        legacy_records.len()
+                    );
+                }
+                for (client_id, mut record) in legacy_records {
+                    let mut payload = [&[LEGACY_CLIENT_DATA_VERSION], &record.0[..]].concat();
+                    record.0.zeroize();
+                    let sealed = EncryptedRecord(master_key.seal(&payload, client_id.as_bytes()));
+                    payload.zeroize();
+                    tx.upsert::<Clients>(client_id, sealed)?;
+                }
+                tx.upsert::<KeyCheck>(
+                    KEY_CHECK_KEY.to_string(),
+                    EncryptedRecord(master_key.seal(KEY_CHECK_VALUE, KEY_CHECK_KEY.as_bytes())),
+                )?;
+            }
+        }
+        tx.commit()?;
+
+        Ok(MdbxDatabase {
+            db,
+            master_key: RwLock::new(master_key),
+        })
+    }
+
+    /// Re-encrypts every client record, found output and queued webhook
+    /// under `new_key` in one transaction and switches to it. Returns the
+    /// number of client records re-encrypted.
+    pub fn rotate_master_key(&self, new_key: MasterKey) -> Result<usize> {
+        let mut master_key = self.master_key.write().unwrap();
+        let tx = self.db.begin_readwrite()?;
+        let records = tx
+            .cursor::<Clients>()?
+            .walk(None)
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let count = records.len();
+        for (client_id, record) in records {
+            let mut plaintext = master_key.open(&record.0, client_id.as_bytes())?;
+            let record = EncryptedRecord(new_key.seal(&plaintext, client_id.as_bytes()));
+            plaintext.zeroize();
+            tx.upsert::<Clients>(client_id, record)?;
+        }
+        // Found outputs are keyed by a tag under the master key, so they move
+        // to new keys as well
+        let found_outputs = tx
+            .cursor::<FoundOutputs>()?
+            .walk(None)
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        for (key, record) in found_outputs {
+            let output: FoundOutput = open_record(&master_key, &record, &key.clone().encode())?;
+            tx.del::<FoundOutputs>(key.clone(), None)?;
+            let key = FoundOutputKey::new(&new_key, &key.client_id, output.height, &output.utxo)?;
+            let record = seal_record(&new_key, &output, &key.clone().encode())?;
+            tx.upsert::<FoundOutputs>(key, record)?;
+        }
+        let webhooks = tx
+            .cursor::<WebhookDeliveries>()?
+            .walk(None)
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        for (id, record) in webhooks {
+            let delivery: WebhookDelivery = open_record(&master_key, &record, id.as_bytes())?;
+            let record = seal_record(&new_key, &delivery, id.as_bytes())?;
+            tx.upsert::<WebhookDeliveries>(id, record)?;
+        }
+        tx.upsert::<KeyCheck>(
+            KEY_CHECK_KEY.to_string(),
+            EncryptedRecord(new_key.seal(KEY_CHECK_VALUE, KEY_CHECK_KEY.as_bytes())),
+        )?;
+        tx.commit()?;
+        *master_key = new_key;
+        Ok(count)
+    }
+
+    fn seal_client(&self, client_id: &str, client_data: &ClientData) -> Result<EncryptedRecord> {
+        let mut plaintext = encode_client_data(client_data)?;
+        let record = EncryptedRecord(
+            self.master_key
+                .read()
+                .unwrap()
+                .seal(&plaintext, client_id.as_bytes()),
+        );
+        plaintext.zeroize();
+        Ok(record)
     }
 }
 
+/// Seals the bincode encoding of `value`, binding it to `aad`.
+fn seal_record<T: Serialize>(
+    master_key: &MasterKey,
+    value: &T,
+    aad: &[u8],
+) -> Result<EncryptedRecord> {
+    let mut plaintext = bincode::serialize(value).map_err(anyhow::Error::from)?;
+    let record = EncryptedRecord(master_key.seal(&plaintext, aad));
+    plaintext.zeroize();
+    Ok(record)
+}
+
+/// Opens a record written by `seal_record` with the same `aad`.
+fn open_record<T: DeserializeOwned>(
+    master_key: &MasterKey,
+    record: &EncryptedRecord,
+    aad: &[u8],
+) -> Result<T> {
+    let mut plaintext = master_key.open(&record.0, aad)?;
+    let value = bincode::deserialize(&plaintext).map_err(anyhow::Error::from);
+    plaintext.zeroize();
+    Ok(value?)
+}
+
+/// Writes a client record the way it was stored before encryption, for
+/// testing the migration in `MdbxDatabase::new`.
+#[cfg(test)]
+pub(super) fn write_plaintext_client(
+    path: PathBuf,
+    client_id: &str,
+    plaintext: Vec<u8>,
+) -> Result<()> {
+    let db = OrmDatabase::create(Some(path), &TABLES)?;
+    let tx = db.begin_readwrite()?;
+    tx.upsert::<Clients>(client_id.to_string(), EncryptedRecord(plaintext))?;
+    tx.commit()?;
+    Ok(())
+}
+
 #[async_trait]
 impl UtxoStore for MdbxDatabase {
     async fn add_utxo(&self, block_height: u64, utxo: UTXO) -> Result<()> {
         let tx = self.db.begin_readwrite()?;
-        tx.upsert::<UTXOs>(block_height, utxo)?;
+        tx.upsert::<OutPoints>(outpoint_key(&utxo), block_height)?;
+        tx.upsert::<UTXOs>(UtxoKey::new(block_height, &utxo), utxo)?;
         tx.commit()?;
         Ok(())
     }
@@ -76,28 +651,522 @@ impl UtxoStore for MdbxDatabase {
     async fn query_utxos(&self, block_height: u64) -> Result<Vec<UTXO>> {
         let tx = self.db.begin_read()?;
         let cursor = tx.cursor::<UTXOs>()?;
-        Ok(cursor
-            .walk(Some(block_height))
-            .map(|result| Ok(result?.1))
-            .collect::<Result<Vec<UTXO>>>()?)
+        let mut utxos = Vec::new();
+        for item in cursor.walk(Some(UtxoKey::block_start(block_height))) {
+            let (key, utxo) = item?;
+            if key.height != block_height {
+                break;
+            }
+            utxos.push(utxo);
+        }
+        Ok(utxos)
+    }
+
+    async fn query_utxos_range(
+        &self,
+        start_height: u64,
+        end_height: u64,
+    ) -> Result<Vec<(u64, Vec<UTXO>)>> {
+        let tx = self.db.begin_read()?;
+        let cursor = tx.cursor::<UTXOs>()?;
+        let mut blocks: Vec<(u64, Vec<UTXO>)> = Vec::new();
+        for item in cursor.walk(Some(UtxoKey::block_start(start_height))) {
+            let (key, utxo) = item?;
+            if key.height > end_height {
+                break;
+            }
+            match blocks.last_mut() {
+                Some((height, utxos)) if *height == key.height => utxos.push(utxo),
+                _ => blocks.push((key.height, vec![utxo])),
+            }
+        }
+        Ok(blocks)
+    }
+
+    async fn get_utxo(&self, txid: [u8; 32], vout: u32) -> Result<Option<(u64, UTXO)>> {
+        let tx = self.db.begin_read()?;
+        let Some(height) = tx.get::<OutPoints>(OutPointKey { txid, vout })? else {
+            return Ok(None);
+        };
+        let utxo = tx.get::<UTXOs>(UtxoKey { height, txid, vout })?;
+        Ok(utxo.map(|utxo| (height, utxo)))
+    }
+
+    async fn get_block_info(&self, block_height: u64) -> Result<Option<BlockInfo>> {
+        let tx = self.db.begin_read()?;
+        Ok(tx.get::<Blocks>(block_height)?)
+    }
+
+    async fn get_filter(&self, block_height: u64) -> Result<Option<BlockFilter>> {
+        let tx = self.db.begin_read()?;
+        Ok(tx.get::<Filters>(block_height)?)
+    }
+
+    async fn get_filters(
+        &self,
+        start_height: u64,
+        end_height: u64,
+    ) -> Result<Vec<(u64, BlockFilter)>> {
+        let tx = self.db.begin_read()?;
+        let mut filters = Vec::new();
+        for item in tx.cursor::<Filters>()?.walk(Some(start_height)) {
+            let (height, filter) = item?;
+            if height > end_height {
+                break;
+            }
+            filters.push((height, filter));
+        }
+        Ok(filters)
+    }
+
+    async fn get_spend(&self, txid: [u8; 32], vout: u32) -> Result<Option<SpentInfo>> {
+        let tx = self.db.begin_read()?;
+        Ok(tx.get::<Spends>(OutPointKey { txid, vout })?)
+    }
+
+    async fn get_spends(&self, outpoints: &[([u8; 32], u32)]) -> Result<Vec<Option<SpentInfo>>> {
+        let tx = self.db.begin_read()?;
+        outpoints
+            .iter()
+            .map(|&(txid, vout)| Ok(tx.get::<Spends>(OutPointKey { txid, vout })?))
+            .collect()
+    }
+
+    async fn tip(&self) -> Result<Option<(u64, BlockInfo)>> {
+        let tx = self.db.begin_read()?;
+        let mut cursor = tx.cursor::<Blocks>()?;
+        Ok(cursor.last()?)
+    }
+
+    async fn rollback_to(&self, block_height: u64) -> Result<()> {
+        let tx = self.db.begin_readwrite()?;
+        let stale_utxos = tx
+            .cursor::<UTXOs>()?
+            .walk(Some(UtxoKey::block_start(block_height + 1)))
+            .map(|item| Ok(item?.0))
+            .collect::<Result<Vec<_>>>()?;
+        for key in stale_utxos {
+            tx.del::<OutPoints>(
+                OutPointKey {
+                    txid: key.txid,
+                    vout: key.vout,
+                },
+                None,
+            )?;
+            tx.del::<UTXOs>(key, None)?;
+        }
+        let stale_blocks = tx
+            .cursor::<Blocks>()?
+            .walk(Some(block_height + 1))
+            .map(|item| Ok(item?.0))
+            .collect::<Result<Vec<_>>>()?;
+        for height in stale_blocks {
+            tx.del::<Blocks>(height, None)?;
+        }
+        let stale_filters = tx
+            .cursor::<Filters>()?
+            .walk(Some(block_height + 1))
+            .map(|item| Ok(item?.0))
+            .collect::<Result<Vec<_>>>()?;
+        for height in stale_filters {
+            tx.del::<Filters>(height, None)?;
+        }
+        let stale_spends = tx
+            .cursor::<SpendsByHeight>()?
+            .walk(Some(UtxoKey::block_start(block_height + 1)))
+            .map(|item| Ok(item?.0))
+            .collect::<Result<Vec<_>>>()?;
+        for key in stale_spends {
+            tx.del::<Spends>(
+                OutPointKey {
+                    txid: key.txid,
+                    vout: key.vout,
+                },
+                None,
+            )?;
+            tx.del::<SpendsByHeight>(key, None)?;
+        }
+        let index_state = tx.get::<IndexStates>(INDEX_STATE_KEY.to_string())?;
+        if index_state.is_some_and(|state| state.height > block_height) {
+            match tx.get::<Blocks>(block_height)? {
+                Some(block) => tx.upsert::<IndexStates>(
+                    INDEX_STATE_KEY.to_string(),
+                    IndexState {
+                        height: block_height,
+                        block_hash: block.hash,
+                    },
+                )?,
+                None => {
+                    tx.del::<IndexStates>(INDEX_STATE_KEY.to_string(), None)?;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn add_block(&self, block: IndexedBlock) -> Result<()> {
+        let tx = self.db.begin_readwrite()?;
+        let mut replaced_utxos = Vec::new();
+        for item in tx
+            .cursor::<UTXOs>()?
+            .walk(Some(UtxoKey::block_start(block.height)))
+        {
+            let (key, _) = item?;
+            if key.height != block.height {
+                break;
+            }
+            replaced_utxos.push(key);
+        }
+        for key in replaced_utxos {
+            let outpoint = OutPointKey {
+                txid: key.txid,
+                vout: key.vout,
+            };
+            // A later block may have spent an output this block no longer has
+            if let Some(spend) = tx.get::<Spends>(outpoint)? {
+                tx.del::<SpendsByHeight>(
+                    UtxoKey {
+                        height: spend.height,
+                        txid: key.txid,
+                        vout: key.vout,
+                    },
+                    None,
+                )?;
+                tx.del::<Spends>(outpoint, None)?;
+            }
+            tx.del::<OutPoints>(outpoint, None)?;
+            tx.del::<UTXOs>(key, None)?;
+        }
+        let mut replaced_spends = Vec::new();
+        for item in tx
+            .cursor::<SpendsByHeight>()?
+            .walk(Some(UtxoKey::block_start(block.height)))
+        {
+            let (key, _) = item?;
+            if key.height != block.height {
+                break;
+            }
+            replaced_spends.push(key);
+        }
+        for key in replaced_spends {
+            tx.del::<Spends>(
+                OutPointKey {
+                    txid: key.txid,
+                    vout: key.vout,
+                },
+                None,
+            )?;
+            tx.del::<SpendsByHeight>(key, None)?;
+        }
+
+        for utxo in block.utxos {
+            tx.upsert::<OutPoints>(outpoint_key(&utxo), block.height)?;
+            tx.upsert::<UTXOs>(UtxoKey::new(block.height, &utxo), utxo)?;
+        }
+        for (txid, vout, spend) in block.spends {
+            let outpoint = OutPointKey { txid, vout };
+            if tx.get::<OutPoints>(outpoint)?.is_none() {
+                continue;
+            }
+            tx.upsert::<Spends>(outpoint, spend)?;
+            tx.upsert::<SpendsByHeight>(
+                UtxoKey {
+                    height: spend.height,
+                    txid,
+                    vout,
+                },
+                spend,
+            )?;
+        }
+        tx.upsert::<Filters>(block.height, block.filter)?;
+        tx.upsert::<Blocks>(block.height, block.info)?;
+        tx.upsert::<IndexStates>(
+            INDEX_STATE_KEY.to_string(),
+            IndexState {
+                height: block.height,
+                block_hash: block.info.hash,
+            },
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn index_state(&self) -> Result<Option<IndexState>> {
+        let tx = self.db.begin_read()?;
+        Ok(tx.get::<IndexStates>(INDEX_STATE_KEY.to_string())?)
     }
 }
 
 #[async_trait]
 impl ClientStore for MdbxDatabase {
     async fn store_client_data(&self, client_id: &str, client_data: ClientData) -> Result<()> {
+        let record = self.seal_client(client_id, &client_data)?;
         let tx = self.db.begin_readwrite()?;
         let mut cursor = tx.cursor::<Clients>()?;
-        cursor.upsert(client_id.to_string(), client_data)?;
+        cursor.upsert(client_id.to_string(), record)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn update_client(&self, client_id: &str, client_data: ClientData) -> Result<()> {
+        let record = self.seal_client(client_id, &client_data)?;
+        let tx = self.db.begin_readwrite()?;
+        if tx.get::<Clients>(client_id.to_string())?.is_none() {
+            return Err(Error::ClientNotFound);
+        }
+        tx.upsert::<Clients>(client_id.to_string(), record)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn delete_client(&self, client_id: &str) -> Result<()> {
+        let tx = self.db.begin_readwrite()?;
+        if tx.get::<Clients>(client_id.to_string())?.is_none() {
+            return Err(Error::ClientNotFound);
+        }
+        let found_outputs = tx
+            .cursor::<FoundOutputs>()?
+            .walk(Some(FoundOutputKey::client_start(client_id)?))
+            .map(|item| Ok(item?.0))
+            .take_while(|key: &Result<FoundOutputKey>| {
+                key.as_ref().map_or(true, |key| key.client_id == client_id)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        for key in found_outputs {
+            tx.del::<FoundOutputs>(key, None)?;
+        }
+        let master_key = self.master_key.read().unwrap();
+        let webhooks = tx
+            .cursor::<WebhookDeliveries>()?
+            .walk(None)
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        for (id, record) in webhooks {
+            let delivery: WebhookDelivery = open_record(&master_key, &record, id.as_bytes())?;
+            if delivery.client_id == client_id {
+                tx.del::<WebhookDeliveries>(id, None)?;
+            }
+        }
+        tx.del::<Clients>(client_id.to_string(), None)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn list_clients(&self) -> Result<Vec<String>> {
+        let tx = self.db.begin_read()?;
+        let client_ids = tx
+            .cursor::<Clients>()?
+            .walk(None)
+            .map(|item| Ok(item?.0))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(client_ids)
+    }
+
+    async fn add_found_outputs(
+        &self,
+        client_id: &str,
+        height: u64,
+        outputs: Vec<MatchedOutput>,
+    ) -> Result<()> {
+        let master_key = self.master_key.read().unwrap();
+        let tx = self.db.begin_readwrite()?;
+        for MatchedOutput { utxo, label } in outputs {
+            let key = FoundOutputKey::new(&master_key, client_id, height, &utxo)?;
+            let output = FoundOutput {
+                height,
+                utxo,
+                label,
+            };
+            let record = seal_record(&master_key, &output, &key.clone().encode())?;
+            tx.upsert::<FoundOutputs>(key, record)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn get_found_outputs(&self, client_id: &str) -> Result<Vec<FoundOutput>> {
+        let master_key = self.master_key.read().unwrap();
+        let tx = self.db.begin_read()?;
+        let cursor = tx.cursor::<FoundOutputs>()?;
+        let mut outputs = Vec::new();
+        for item in cursor.walk(Some(FoundOutputKey::client_start(client_id)?)) {
+            let (key, record) = item?;
+            if key.client_id != client_id {
+                break;
+            }
+            outputs.push(open_record(&master_key, &record, &key.encode())?);
+        }
+        outputs.sort_by_key(found_output_order);
+        Ok(outputs)
+    }
+
+    async fn found_outputs_page(
+        &self,
+        client_id: &str,
+        after: Option<(u64, [u8; 32], u32)>,
+        limit: usize,
+    ) -> Result<Vec<FoundOutput>> {
+        let master_key = self.master_key.read().unwrap();
+        let start_height = after.map_or(0, |(height, _, _)| height);
+        let start = FoundOutputKey::height_start(client_id, start_height)?;
+        let tx = self.db.begin_read()?;
+        let cursor = tx.cursor::<FoundOutputs>()?;
+        let mut outputs = Vec::new();
+        // The outputs of one height, sorted as a whole since tags are not in
+        // outpoint order
+        let mut height_outputs: Vec<FoundOutput> = Vec::new();
+        for item in cursor.walk(Some(start)) {
+            let (key, record) = item?;
+            if key.client_id != client_id {
+                break;
+            }
+            if height_outputs
+                .first()
+                .is_some_and(|output| output.height != key.height)
+            {
+                push_page_outputs(&mut outputs, &mut height_outputs, after);
+                if outputs.len() >= limit {
+                    break;
+                }
+            }
+            height_outputs.push(open_record(&master_key, &record, &key.encode())?);
+        }
+        push_page_outputs(&mut outputs, &mut height_outputs, after);
+        outputs.truncate(limit);
+        Ok(outputs)
+    }
+
+    async fn rollback_found_outputs(&self, height: u64) -> Result<Vec<(String, FoundOutput)>> {
+        let master_key = self.master_key.read().unwrap();
+        let tx = self.db.begin_readwrite()?;
+        let found_outputs = tx
+            .cursor::<FoundOutputs>()?
+            .walk(None)
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let mut removed = Vec::new();
+        for (key, record) in found_outputs {
+            if key.height > height {
+                let output = open_record(&master_key, &record, &key.clone().encode())?;
+                removed.push((key.client_id.clone(), output));
+                tx.del::<FoundOutputs>(key, None)?;
+            }
+        }
+        tx.commit()?;
+        Ok(removed)
+    }
+
+    async fn queue_webhook(&self, delivery: WebhookDelivery) -> Result<()> {
+        let record = seal_record(
+            &self.master_key.read().unwrap(),
+            &delivery,
+            delivery.id.as_bytes(),
+        )?;
+        let tx = self.db.begin_readwrite()?;
+        tx.upsert::<WebhookDeliveries>(delivery.id, record)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn pending_webhooks(&self) -> Result<Vec<WebhookDelivery>> {
+        let master_key = self.master_key.read().unwrap();
+        let tx = self.db.begin_read()?;
+        let deliveries = tx
+            .cursor::<WebhookDeliveries>()?
+            .walk(None)
+            .map(|item| {
+                let (id, record) = item?;
+                open_record(&master_key, &record, id.as_bytes())
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(deliveries)
+    }
+
+    async fn remove_webhook(&self, id: &str) -> Result<()> {
+        let tx = self.db.begin_readwrite()?;
+        tx.del::<WebhookDeliveries>(id.to_string(), None)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn scan_state(&self) -> Result<Option<IndexState>> {
+        let tx = self.db.begin_read()?;
+        Ok(tx.get::<IndexStates>(SCAN_STATE_KEY.to_string())?)
+    }
+
+    async fn set_scan_state(&self, state: Option<IndexState>) -> Result<()> {
+        let tx = self.db.begin_readwrite()?;
+        match state {
+            Some(state) => tx.upsert::<IndexStates>(SCAN_STATE_KEY.to_string(), state)?,
+            None => {
+                tx.del::<IndexStates>(SCAN_STATE_KEY.to_string(), None)?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn add_failed_scan(&self, client_id: &str, height: u64) -> Result<()> {
+        let tx = self.db.begin_readwrite()?;
+        let mut client_ids = tx.get::<FailedScans>(height)?.unwrap_or_default();
+        if !client_ids.0.iter().any(|id| id == client_id) {
+            client_ids.0.push(client_id.to_string());
+            tx.upsert::<FailedScans>(height, client_ids)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn failed_scans(&self) -> Result<Vec<(u64, String)>> {
+        let tx = self.db.begin_read()?;
+        let mut failed = Vec::new();
+        for item in tx.cursor::<FailedScans>()?.walk(None) {
+            let (height, client_ids) = item?;
+            failed.extend(client_ids.0.into_iter().map(|id| (height, id)));
+        }
+        Ok(failed)
+    }
+
+    async fn remove_failed_scan(&self, client_id: &str, height: u64) -> Result<()> {
+        let tx = self.db.begin_readwrite()?;
+        let Some(mut client_ids) = tx.get::<FailedScans>(height)? else {
+            return Ok(());
+        };
+        client_ids.0.retain(|id| id != client_id);
+        if client_ids.0.is_empty() {
+            tx.del::<FailedScans>(height, None)?;
+        } else {
+            tx.upsert::<FailedScans>(height, client_ids)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn rollback_failed_scans(&self, height: u64) -> Result<()> {
+        let tx = self.db.begin_readwrite()?;
+        let stale = tx
+            .cursor::<FailedScans>()?
+            .walk(Some(height + 1))
+            .map(|item| Ok(item?.0))
+            .collect::<Result<Vec<_>>>()?;
+        for height in stale {
+            tx.del::<FailedScans>(height, None)?;
+        }
         tx.commit()?;
         Ok(())
     }
 
     async fn get_client_data(&self, client_id: &str) -> Result<ClientData> {
         let tx = self.db.begin_read()?;
-        let client_data: ClientData = tx
+        let record = tx
             .get::<Clients>(client_id.to_string())?
             .ok_or(Error::ClientNotFound)?;
-        Ok(client_data)
+        let mut plaintext = self
+            .master_key
+            .read()
+            .unwrap()
+            .open(&record.0, client_id.as_bytes())?;
+        let client_data = decode_client_data(&plaintext);
+        plaintext.zeroize();
+        client_data
     }
 }
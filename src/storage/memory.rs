@@ -1,21 +1,45 @@
 use super::{ClientStore, UtxoStore};
-use crate::models::{ClientData, UTXO};
+use crate::models::{
+    BlockFilter, BlockInfo, ClientData, FoundOutput, IndexState, IndexedBlock, MatchedOutput,
+    SpentInfo, WebhookDelivery, UTXO,
+};
 use crate::{Error, Result};
 use async_trait::async_trait;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
+/// Keeps everything in memory. Client records are not encrypted since
+/// nothing is persisted.
 pub struct MemoryStore {
-    utxos: Arc<RwLock<HashMap<u64, Vec<UTXO>>>>,
+    utxos: Arc<RwLock<BTreeMap<u64, Vec<UTXO>>>>,
+    blocks: Arc<RwLock<BTreeMap<u64, BlockInfo>>>,
+    filters: Arc<RwLock<BTreeMap<u64, BlockFilter>>>,
+    spends: Arc<RwLock<HashMap<([u8; 32], u32), SpentInfo>>>,
+    /// Height of each indexed output, by outpoint.
+    outpoints: Arc<RwLock<HashMap<([u8; 32], u32), u64>>>,
+    index_state: Arc<RwLock<Option<IndexState>>>,
     clients: Arc<RwLock<HashMap<String, ClientData>>>,
+    found_outputs: Arc<RwLock<HashMap<String, BTreeMap<([u8; 32], u32), FoundOutput>>>>,
+    webhooks: Arc<RwLock<BTreeMap<String, WebhookDelivery>>>,
+    scan_state: Arc<RwLock<Option<IndexState>>>,
+    failed_scans: Arc<RwLock<BTreeSet<(u64, String)>>>,
 }
 
 impl MemoryStore {
     pub fn new() -> Self {
         Self {
-            utxos: Arc::new(RwLock::new(HashMap::new())),
+            utxos: Arc::new(RwLock::new(BTreeMap::new())),
+            blocks: Arc::new(RwLock::new(BTreeMap::new())),
+            filters: Arc::new(RwLock::new(BTreeMap::new())),
+            spends: Arc::new(RwLock::new(HashMap::new())),
+            outpoints: Arc::new(RwLock::new(HashMap::new())),
+            index_state: Arc::new(RwLock::new(None)),
             clients: Arc::new(RwLock::new(HashMap::new())),
+            found_outputs: Arc::new(RwLock::new(HashMap::new())),
+            webhooks: Arc::new(RwLock::new(BTreeMap::new())),
+            scan_state: Arc::new(RwLock::new(None)),
+            failed_scans: Arc::new(RwLock::new(BTreeSet::new())),
         }
     }
 }
@@ -24,6 +48,8 @@ impl MemoryStore {
 impl UtxoStore for MemoryStore {
     async fn add_utxo(&self, block_height: u64, utxo: UTXO) -> Result<()> {
         let mut utxos = self.utxos.write().await;
+        let mut outpoints = self.outpoints.write().await;
+        outpoints.insert((utxo.txid, utxo.vout), block_height);
         utxos
             .entry(block_height)
             .or_insert_with(Vec::new)
@@ -35,6 +61,130 @@ impl UtxoStore for MemoryStore {
         let utxos = self.utxos.read().await;
         Ok(utxos.get(&block_height).cloned().unwrap_or_default())
     }
+
+    async fn query_utxos_range(
+        &self,
+        start_height: u64,
+        end_height: u64,
+    ) -> Result<Vec<(u64, Vec<UTXO>)>> {
+        let utxos = self.utxos.read().await;
+        Ok(utxos
+            .range(start_height..=end_height)
+            .filter(|(_, utxos)| !utxos.is_empty())
+            .map(|(height, utxos)| (*height, utxos.clone()))
+            .collect())
+    }
+
+    async fn get_utxo(&self, txid: [u8; 32], vout: u32) -> Result<Option<(u64, UTXO)>> {
+        let utxos = self.utxos.read().await;
+        let outpoints = self.outpoints.read().await;
+        let Some(&height) = outpoints.get(&(txid, vout)) else {
+            return Ok(None);
+        };
+        Ok(utxos
+            .get(&height)
+            .into_iter()
+            .flatten()
+            .find(|utxo| utxo.txid == txid && utxo.vout == vout)
+            .map(|utxo| (height, utxo.clone())))
+    }
+
+    async fn get_block_info(&self, block_height: u64) -> Result<Option<BlockInfo>> {
+        let blocks = self.blocks.read().await;
+        Ok(blocks.get(&block_height).copied())
+    }
+
+    async fn get_filter(&self, block_height: u64) -> Result<Option<BlockFilter>> {
+        let filters = self.filters.read().await;
+        Ok(filters.get(&block_height).cloned())
+    }
+
+    async fn get_filters(
+        &self,
+        start_height: u64,
+        end_height: u64,
+    ) -> Result<Vec<(u64, BlockFilter)>> {
+        let filters = self.filters.read().await;
+        Ok(filters
+            .range(start_height..=end_height)
+            .map(|(height, filter)| (*height, filter.clone()))
+            .collect())
+    }
+
+    async fn get_spend(&self, txid: [u8; 32], vout: u32) -> Result<Option<SpentInfo>> {
+        let spends = self.spends.read().await;
+        Ok(spends.get(&(txid, vout)).copied())
+    }
+
+    async fn get_spends(&self, outpoints: &[([u8; 32], u32)]) -> Result<Vec<Option<SpentInfo>>> {
+        let spends = self.spends.read().await;
+        Ok(outpoints
+            .iter()
+            .map(|outpoint| spends.get(outpoint).copied())
+            .collect())
+    }
+
+    async fn tip(&self) -> Result<Option<(u64, BlockInfo)>> {
+        let blocks = self.blocks.read().await;
+        Ok(blocks
+            .last_key_value()
+            .map(|(height, block)| (*height, *block)))
+    }
+
+    async fn rollback_to(&self, block_height: u64) -> Result<()> {
+        let mut utxos = self.utxos.write().await;
+        let mut blocks = self.blocks.write().await;
+        let mut filters = self.filters.write().await;
+        let mut spends = self.spends.write().await;
+        let mut outpoints = self.outpoints.write().await;
+        let mut index_state = self.index_state.write().await;
+        utxos.retain(|height, _| *height <= block_height);
+        outpoints.retain(|_, height| *height <= block_height);
+        blocks.retain(|height, _| *height <= block_height);
+        filters.retain(|height, _| *height <= block_height);
+        spends.retain(|_, spend| spend.height <= block_height);
+        if index_state.is_some_and(|state| state.height > block_height) {
+            *index_state = blocks.get(&block_height).map(|block| IndexState {
+                height: block_height,
+                block_hash: block.hash,
+            });
+        }
+        Ok(())
+    }
+
+    async fn add_block(&self, block: IndexedBlock) -> Result<()> {
+        let mut utxos = self.utxos.write().await;
+        let mut blocks = self.blocks.write().await;
+        let mut filters = self.filters.write().await;
+        let mut spends = self.spends.write().await;
+        let mut outpoints = self.outpoints.write().await;
+        let mut index_state = self.index_state.write().await;
+        for utxo in utxos.get(&block.height).into_iter().flatten() {
+            outpoints.remove(&(utxo.txid, utxo.vout));
+            spends.remove(&(utxo.txid, utxo.vout));
+        }
+        spends.retain(|_, spend| spend.height != block.height);
+        for utxo in &block.utxos {
+            outpoints.insert((utxo.txid, utxo.vout), block.height);
+        }
+        utxos.insert(block.height, block.utxos);
+        for (txid, vout, spend) in block.spends {
+            if outpoints.contains_key(&(txid, vout)) {
+                spends.insert((txid, vout), spend);
+            }
+        }
+        filters.insert(block.height, block.filter);
+        blocks.insert(block.height, block.info);
+        *index_state = Some(IndexState {
+            height: block.height,
+            block_hash: block.info.hash,
+        });
+        Ok(())
+    }
+
+    async fn index_state(&self) -> Result<Option<IndexState>> {
+        Ok(*self.index_state.read().await)
+    }
 }
 
 #[async_trait]
@@ -49,4 +199,141 @@ impl ClientStore for MemoryStore {
         let clients = self.clients.read().await;
         clients.get(client_id).cloned().ok_or(Error::ClientNotFound)
     }
+
+    async fn update_client(&self, client_id: &str, client_data: ClientData) -> Result<()> {
+        let mut clients = self.clients.write().await;
+        let client = clients.get_mut(client_id).ok_or(Error::ClientNotFound)?;
+        *client = client_data;
+        Ok(())
+    }
+
+    async fn delete_client(&self, client_id: &str) -> Result<()> {
+        let mut clients = self.clients.write().await;
+        let mut found_outputs = self.found_outputs.write().await;
+        let mut webhooks = self.webhooks.write().await;
+        found_outputs.remove(client_id);
+        webhooks.retain(|_, delivery| delivery.client_id != client_id);
+        clients
+            .remove(client_id)
+            .map(|_| ())
+            .ok_or(Error::ClientNotFound)
+    }
+
+    async fn list_clients(&self) -> Result<Vec<String>> {
+        let clients = self.clients.read().await;
+        Ok(clients.keys().cloned().collect())
+    }
+
+    async fn add_found_outputs(
+        &self,
+        client_id: &str,
+        height: u64,
+        outputs: Vec<MatchedOutput>,
+    ) -> Result<()> {
+        let mut found_outputs = self.found_outputs.write().await;
+        let found = found_outputs.entry(client_id.to_string()).or_default();
+        for MatchedOutput { utxo, label } in outputs {
+            found.insert(
+                (utxo.txid, utxo.vout),
+                FoundOutput {
+                    height,
+                    utxo,
+                    label,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    async fn get_found_outputs(&self, client_id: &str) -> Result<Vec<FoundOutput>> {
+        let found_outputs = self.found_outputs.read().await;
+        let mut outputs: Vec<FoundOutput> = found_outputs
+            .get(client_id)
+            .map(|outputs| outputs.values().cloned().collect())
+            .unwrap_or_default();
+        outputs.sort_by_key(|output| (output.height, output.utxo.txid, output.utxo.vout));
+        Ok(outputs)
+    }
+
+    async fn found_outputs_page(
+        &self,
+        client_id: &str,
+        after: Option<(u64, [u8; 32], u32)>,
+        limit: usize,
+    ) -> Result<Vec<FoundOutput>> {
+        Ok(self
+            .get_found_outputs(client_id)
+            .await?
+            .into_iter()
+            .filter(|output| {
+                after.map_or(true, |after| {
+                    (output.height, output.utxo.txid, output.utxo.vout) > after
+                })
+            })
+            .take(limit)
+            .collect())
+    }
+
+    async fn rollback_found_outputs(&self, height: u64) -> Result<Vec<(String, FoundOutput)>> {
+        let mut found_outputs = self.found_outputs.write().await;
+        let mut removed = Vec::new();
+        for (client_id, outputs) in found_outputs.iter_mut() {
+            outputs.retain(|_, output| {
+                if output.height <= height {
+                    return true;
+                }
+                removed.push((client_id.clone(), output.clone()));
+                false
+            });
+        }
+        Ok(removed)
+    }
+    async fn queue_webhook(&self, delivery: WebhookDelivery) -> Result<()> {
+        let mut webhooks = self.webhooks.write().await;
+        webhooks.insert(delivery.id.clone(), delivery);
+        Ok(())
+    }
+
+    async fn pending_webhooks(&self) -> Result<Vec<WebhookDelivery>> {
+        let webhooks = self.webhooks.read().await;
+        Ok(webhooks.values().cloned().collect())
+    }
+
+    async fn remove_webhook(&self, id: &str) -> Result<()> {
+        let mut webhooks = self.webhooks.write().await;
+        webhooks.remove(id);
+        Ok(())
+    }
+
+    async fn scan_state(&self) -> Result<Option<IndexState>> {
+        Ok(*self.scan_state.read().await)
+    }
+
+    async fn set_scan_state(&self, state: Option<IndexState>) -> Result<()> {
+        *self.scan_state.write().await = state;
+        Ok(())
+    }
+
+    async fn add_failed_scan(&self, client_id: &str, height: u64) -> Result<()> {
+        let mut failed_scans = self.failed_scans.write().await;
+        failed_scans.insert((height, client_id.to_string()));
+        Ok(())
+    }
+
+    async fn failed_scans(&self) -> Result<Vec<(u64, String)>> {
+        let failed_scans = self.failed_scans.read().await;
+        Ok(failed_scans.iter().cloned().collect())
+    }
+
+    async fn remove_failed_scan(&self, client_id: &str, height: u64) -> Result<()> {
+        let mut failed_scans = self.failed_scans.write().await;
+        failed_scans.remove(&(height, client_id.to_string()));
+        Ok(())
+    }
+
+    async fn rollback_failed_scans(&self, height: u64) -> Result<()> {
+        let mut failed_scans = self.failed_scans.write().await;
+        failed_scans.retain(|(failed, _)| *failed <= height);
+        Ok(())
+    }
 }
@@ -1,10 +1,15 @@
+mod encryption;
 mod mdbx;
 mod memory;
 
+pub use encryption::MasterKey;
 pub use mdbx::MdbxDatabase;
 pub use memory::MemoryStore;
 
-use crate::models::{ClientData, UTXO};
+use crate::models::{
+    BlockFilter, BlockInfo, ClientData, FoundOutput, IndexState, IndexedBlock, MatchedOutput,
+    SpentInfo, WebhookDelivery, UTXO,
+};
 use crate::Result;
 use async_trait::async_trait;
 
@@ -12,21 +17,105 @@ use async_trait::async_trait;
 pub trait UtxoStore: Send + Sync {
     async fn add_utxo(&self, block_height: u64, utxo: UTXO) -> Result<()>;
     async fn query_utxos(&self, block_height: u64) -> Result<Vec<UTXO>>;
+    /// Returns the UTXOs of each block in `start_height..=end_height` that
+    /// has any, grouped by height in ascending order.
+    async fn query_utxos_range(
+        &self,
+        start_height: u64,
+        end_height: u64,
+    ) -> Result<Vec<(u64, Vec<UTXO>)>>;
+    /// Looks up an indexed output by outpoint, returning it with its height.
+    async fn get_utxo(&self, txid: [u8; 32], vout: u32) -> Result<Option<(u64, UTXO)>>;
+    async fn get_block_info(&self, block_height: u64) -> Result<Option<BlockInfo>>;
+    async fn get_filter(&self, block_height: u64) -> Result<Option<BlockFilter>>;
+    /// Returns the filter of each block in `start_height..=end_height` that
+    /// has one, in ascending height order.
+    async fn get_filters(
+        &self,
+        start_height: u64,
+        end_height: u64,
+    ) -> Result<Vec<(u64, BlockFilter)>>;
+    async fn get_spend(&self, txid: [u8; 32], vout: u32) -> Result<Option<SpentInfo>>;
+    /// Looks up the spend of each `(txid, vout)` in one read, returning them
+    /// in the same order.
+    async fn get_spends(&self, outpoints: &[([u8; 32], u32)]) -> Result<Vec<Option<SpentInfo>>>;
+    /// Returns the highest indexed block.
+    async fn tip(&self) -> Result<Option<(u64, BlockInfo)>>;
+    /// Removes everything indexed above `block_height`, moving the index
+    /// state back to it.
+    async fn rollback_to(&self, block_height: u64) -> Result<()>;
+    /// Writes everything indexed for a block, replacing what was written at
+    /// its height, and advances the index state to it, atomically. Only
+    /// spends of indexed outputs are recorded.
+    async fn add_block(&self, block: IndexedBlock) -> Result<()>;
+    async fn index_state(&self) -> Result<Option<IndexState>>;
 }
 
 #[async_trait]
 pub trait ClientStore: Send + Sync {
     async fn store_client_data(&self, client_id: &str, client_data: ClientData) -> Result<()>;
     async fn get_client_data(&self, client_id: &str) -> Result<ClientData>;
+    /// Replaces a registered client's data. Fails with
+    /// `Error::ClientNotFound` if the client is not registered.
+    async fn update_client(&self, client_id: &str, client_data: ClientData) -> Result<()>;
+    /// Removes a client, its scan key, its found outputs and its queued
+    /// webhook deliveries. Fails with
+    /// `Error::ClientNotFound` if the client is not registered.
+    async fn delete_client(&self, client_id: &str) -> Result<()>;
+    async fn list_clients(&self) -> Result<Vec<String>>;
+    /// Records outputs found for a client in the block at `height`.
+    async fn add_found_outputs(
+        &self,
+        client_id: &str,
+        height: u64,
+        outputs: Vec<MatchedOutput>,
+    ) -> Result<()>;
+    /// Returns the outputs found for a client, ordered by height, then txid
+    /// and vout.
+    async fn get_found_outputs(&self, client_id: &str) -> Result<Vec<FoundOutput>>;
+    /// Returns up to `limit` of the outputs found for a client, in the order
+    /// of `get_found_outputs`, starting after the output at `after`, given
+    /// as its height, txid and vout.
+    async fn found_outputs_page(
+        &self,
+        client_id: &str,
+        after: Option<(u64, [u8; 32], u32)>,
+        limit: usize,
+    ) -> Result<Vec<FoundOutput>>;
+    /// Drops the outputs found above `height` for every client, returning
+    /// them with their client id.
+    async fn rollback_found_outputs(&self, height: u64) -> Result<Vec<(String, FoundOutput)>>;
+    /// Adds a delivery to the webhook queue, replacing one with the same id.
+    async fn queue_webhook(&self, delivery: WebhookDelivery) -> Result<()>;
+    /// Returns every queued webhook delivery.
+    async fn pending_webhooks(&self) -> Result<Vec<WebhookDelivery>>;
+    /// Removes a delivery from the webhook queue, if it is there.
+    async fn remove_webhook(&self, id: &str) -> Result<()>;
+    /// Returns the last block scanned by the background scanner.
+    async fn scan_state(&self) -> Result<Option<IndexState>>;
+    async fn set_scan_state(&self, state: Option<IndexState>) -> Result<()>;
+    /// Records that the block at `height` could not be scanned for a client,
+    /// so the background scanner can retry it.
+    async fn add_failed_scan(&self, client_id: &str, height: u64) -> Result<()>;
+    /// Returns the recorded failed scans as height and client id, ordered by
+    /// height.
+    async fn failed_scans(&self) -> Result<Vec<(u64, String)>>;
+    /// Forgets a failed scan, if it is recorded.
+    async fn remove_failed_scan(&self, client_id: &str, height: u64) -> Result<()>;
+    /// Forgets the failed scans of blocks above `height`.
+    async fn rollback_failed_scans(&self, height: u64) -> Result<()>;
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::{ClientInfo, ScanSecret};
+    use crate::Error;
     use silentpayments::receiving::Receiver;
     use silentpayments::secp256k1::PublicKey;
     use silentpayments::utils::Network;
     use std::str::FromStr;
+    use std::sync::Arc;
     use tempfile::tempdir;
 
     // Define a trait that both storage backends implement
@@ -43,28 +132,12 @@ mod tests {
     impl TestStorage for MdbxDatabase {
         fn new_for_test() -> Self {
             let temp_dir = tempdir().unwrap();
-            Self::new(temp_dir.path().to_path_buf()).unwrap()
+            Self::new(temp_dir.path().to_path_buf(), MasterKey::generate()).unwrap()
         }
     }
 
-    async fn test_storage_implementation<S: TestStorage + ClientStore + UtxoStore>() {
-        let store = S::new_for_test();
-
-        // Test UTXO storage
-        let utxo = UTXO {
-            txid: [0; 32],
-            vout: 1,
-            amount: 100000,
-            script_pubkey: [1; 32],
-            input_tweak: [2; 33],
-        };
-        store.add_utxo(1, utxo.clone()).await.unwrap();
-        let retrieved_utxos = store.query_utxos(1).await.unwrap();
-        assert_eq!(retrieved_utxos.len(), 1);
-        assert_eq!(retrieved_utxos[0], utxo);
-
-        // Test client data storage
-        let client_data = ClientData {
+    fn test_client_data() -> ClientData {
+        ClientData {
             receiver: Receiver::new(
                 0,
                 PublicKey::from_str(
@@ -82,14 +155,690 @@ mod tests {
                 Network::Mainnet,
             )
             .expect("Cannot create receiver"),
-            b_scan: [0; 32],
+            b_scan: Arc::new(ScanSecret::from([0; 32])),
+            info: ClientInfo {
+                version: 0,
+                scan_pubkey: "03bbc63f12745d3b9e9d24c6cd7a1efebad0a7f469232fbecf31fba7b4f7ddeda8"
+                    .to_string(),
+                spend_pubkey: "0315bb61abed8d5b7b91eee3b4837fe6300d72dfa0a5a0a7d979ac87b81454ae4e"
+                    .to_string(),
+                change_label: "3e9fce73d4e77a4809908e3c3a2e54ee147b9312dc5044a193d1fc85de46e3c1"
+                    .to_string(),
+                network: "mainnet".to_string(),
+                birthday_height: 0,
+                webhook_url: None,
+                labels: vec![],
+            },
+            token_hash: [0; 32],
+            last_scanned_height: None,
+            webhook_secret: None,
+        }
+    }
+
+    fn matched(utxo: UTXO, label: Option<&str>) -> MatchedOutput {
+        MatchedOutput {
+            utxo,
+            label: label.map(str::to_string),
+        }
+    }
+
+    async fn test_storage_implementation<S: TestStorage + ClientStore + UtxoStore>() {
+        let store = S::new_for_test();
+
+        // Test UTXO storage
+        let utxo = UTXO {
+            txid: [0; 32],
+            vout: 1,
+            amount: 100000,
+            script_pubkey: [1; 32],
+            input_tweak: [2; 33],
         };
+        store.add_utxo(1, utxo.clone()).await.unwrap();
+        let retrieved_utxos = store.query_utxos(1).await.unwrap();
+        assert_eq!(retrieved_utxos.len(), 1);
+        assert_eq!(retrieved_utxos[0], utxo);
+
+        // Test client data storage
+        let client_data = test_client_data();
         store
             .store_client_data("test_client", client_data.clone())
             .await
             .unwrap();
         let retrieved_client_data = store.get_client_data("test_client").await.unwrap();
         assert_eq!(retrieved_client_data.b_scan, client_data.b_scan);
+
+        // Updating and deleting only work on registered clients
+        let updated = ClientData {
+            b_scan: Arc::new(ScanSecret::from([5; 32])),
+            ..client_data.clone()
+        };
+        store
+            .update_client("test_client", updated.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            store.get_client_data("test_client").await.unwrap().b_scan,
+            updated.b_scan
+        );
+        assert!(matches!(
+            store.update_client("unknown", updated).await,
+            Err(Error::ClientNotFound)
+        ));
+        assert_eq!(store.list_clients().await.unwrap(), vec!["test_client"]);
+
+        // Found outputs are kept per client and rolled back by height
+        store
+            .add_found_outputs("test_client", 5, vec![matched(utxo.clone(), None)])
+            .await
+            .unwrap();
+        store
+            .add_found_outputs(
+                "test_client",
+                6,
+                vec![matched(
+                    UTXO {
+                        vout: 2,
+                        ..utxo.clone()
+                    },
+                    Some("01"),
+                )],
+            )
+            .await
+            .unwrap();
+        store
+            .add_found_outputs("test_client_2", 6, vec![matched(utxo.clone(), None)])
+            .await
+            .unwrap();
+        let found = store.get_found_outputs("test_client").await.unwrap();
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].height, 5);
+        assert_eq!(found[0].utxo, utxo);
+        assert_eq!(found[1].label.as_deref(), Some("01"));
+
+        // Pages of found outputs continue after the last output of the
+        // previous page
+        let page = store
+            .found_outputs_page("test_client", None, 1)
+            .await
+            .unwrap();
+        assert_eq!(page, found[..1]);
+        let after = (page[0].height, page[0].utxo.txid, page[0].utxo.vout);
+        let page = store
+            .found_outputs_page("test_client", Some(after), 10)
+            .await
+            .unwrap();
+        assert_eq!(page, found[1..]);
+        let after = (page[0].height, page[0].utxo.txid, page[0].utxo.vout);
+        assert!(store
+            .found_outputs_page("test_client", Some(after), 10)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Outputs at the same height are ordered by outpoint, and pages
+        // continue within a height
+        let same_height = [(9, 1), (3, 0), (9, 0), (6, 4)]
+            .into_iter()
+            .map(|(txid, vout)| {
+                matched(
+                    UTXO {
+                        txid: [txid; 32],
+                        vout,
+                        ..utxo.clone()
+                    },
+                    None,
+                )
+            })
+            .collect();
+        store
+            .add_found_outputs("test_client_3", 4, same_height)
+            .await
+            .unwrap();
+        let found = store.get_found_outputs("test_client_3").await.unwrap();
+        let outpoints: Vec<_> = found
+            .iter()
+            .map(|output| (output.utxo.txid[0], output.utxo.vout))
+            .collect();
+        assert_eq!(outpoints, vec![(3, 0), (6, 4), (9, 0), (9, 1)]);
+        let mut paged = Vec::new();
+        let mut after = None;
+        loop {
+            let page = store
+                .found_outputs_page("test_client_3", after, 3)
+                .await
+                .unwrap();
+            let Some(last) = page.last() else {
+                break;
+            };
+            after = Some((last.height, last.utxo.txid, last.utxo.vout));
+            paged.extend(page);
+        }
+        assert_eq!(paged, found);
+
+        let removed = store.rollback_found_outputs(5).await.unwrap();
+        assert_eq!(removed.len(), 2);
+        assert!(removed.iter().all(|(_, output)| output.height == 6));
+        assert_eq!(
+            store.get_found_outputs("test_client").await.unwrap().len(),
+            1
+        );
+        assert!(store
+            .get_found_outputs("test_client_2")
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Webhook deliveries are queued until removed, and replaced by id
+        let delivery = WebhookDelivery {
+            id: "delivery".to_string(),
+            client_id: "test_client".to_string(),
+            url: "http://127.0.0.1:1/hook".to_string(),
+            body: "{}".to_string(),
+            signature: "00".to_string(),
+            attempts: 0,
+            next_attempt: 0,
+        };
+        store.queue_webhook(delivery.clone()).await.unwrap();
+        let retried = WebhookDelivery {
+            attempts: 1,
+            next_attempt: 10,
+            ..delivery.clone()
+        };
+        store.queue_webhook(retried.clone()).await.unwrap();
+        assert_eq!(store.pending_webhooks().await.unwrap(), vec![retried]);
+        store.remove_webhook("delivery").await.unwrap();
+        assert!(store.pending_webhooks().await.unwrap().is_empty());
+        store.queue_webhook(delivery).await.unwrap();
+
+        // The scanner's position is kept until cleared
+        assert_eq!(store.scan_state().await.unwrap(), None);
+        let scan_state = IndexState {
+            height: 6,
+            block_hash: [6; 32],
+        };
+        store.set_scan_state(Some(scan_state)).await.unwrap();
+        assert_eq!(store.scan_state().await.unwrap(), Some(scan_state));
+        store.set_scan_state(None).await.unwrap();
+        assert_eq!(store.scan_state().await.unwrap(), None);
+
+        store.delete_client("test_client").await.unwrap();
+        assert!(store
+            .get_found_outputs("test_client")
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(store.pending_webhooks().await.unwrap().is_empty());
+        assert!(matches!(
+            store.get_client_data("test_client").await,
+            Err(Error::ClientNotFound)
+        ));
+        assert!(matches!(
+            store.delete_client("test_client").await,
+            Err(Error::ClientNotFound)
+        ));
+    }
+
+    fn test_utxo(txid: u8, vout: u32) -> UTXO {
+        UTXO {
+            txid: [txid; 32],
+            vout,
+            amount: 1000 * (vout as u64 + 1),
+            script_pubkey: [txid; 32],
+            input_tweak: [2; 33],
+        }
+    }
+
+    fn sorted(mut utxos: Vec<UTXO>) -> Vec<UTXO> {
+        utxos.sort_by_key(|utxo| (utxo.txid, utxo.vout));
+        utxos
+    }
+
+    async fn test_utxo_store_conformance<S: TestStorage>() {
+        let store = S::new_for_test();
+
+        let block_1 = vec![test_utxo(1, 0)];
+        let block_2 = vec![test_utxo(3, 1), test_utxo(2, 0), test_utxo(3, 0)];
+        let block_4 = vec![test_utxo(4, 0), test_utxo(5, 2)];
+        for (height, utxos) in [(1, &block_1), (2, &block_2), (4, &block_4)] {
+            for utxo in utxos {
+                store.add_utxo(height, utxo.clone()).await.unwrap();
+            }
+        }
+
+        // Every UTXO of a block is returned, and nothing from other blocks
+        assert_eq!(sorted(store.query_utxos(1).await.unwrap()), block_1);
+        assert_eq!(sorted(store.query_utxos(2).await.unwrap()), sorted(block_2));
+        assert_eq!(sorted(store.query_utxos(4).await.unwrap()), sorted(block_4));
+        assert!(store.query_utxos(0).await.unwrap().is_empty());
+        assert!(store.query_utxos(3).await.unwrap().is_empty());
+        assert!(store.query_utxos(5).await.unwrap().is_empty());
+
+        // Ranges are grouped by height and skip empty blocks
+        let range: Vec<_> = store
+            .query_utxos_range(0, 3)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(height, utxos)| (height, sorted(utxos)))
+            .collect();
+        assert_eq!(
+            range,
+            vec![(1, block_1.clone()), (2, sorted(block_2.clone()))]
+        );
+        let range = store.query_utxos_range(3, 100).await.unwrap();
+        assert_eq!(range.len(), 1);
+        assert_eq!(range[0].0, 4);
+        assert!(store.query_utxos_range(5, 100).await.unwrap().is_empty());
+
+        // Outputs are looked up by outpoint
+        assert_eq!(
+            store.get_utxo([3; 32], 1).await.unwrap(),
+            Some((2, test_utxo(3, 1)))
+        );
+        assert_eq!(
+            store.get_utxo([5; 32], 2).await.unwrap(),
+            Some((4, test_utxo(5, 2)))
+        );
+        assert_eq!(store.get_utxo([5; 32], 0).await.unwrap(), None);
+
+        // Spends are looked up by outpoint
+        let spend = SpentInfo {
+            height: 3,
+            txid: [9; 32],
+        };
+        store
+            .add_block(IndexedBlock {
+                utxos: vec![],
+                spends: vec![([3; 32], 1, spend)],
+                ..test_indexed_block(3)
+            })
+            .await
+            .unwrap();
+        assert_eq!(store.get_spend([3; 32], 1).await.unwrap(), Some(spend));
+        assert_eq!(store.get_spend([3; 32], 0).await.unwrap(), None);
+        assert_eq!(
+            store
+                .get_spends(&[([3; 32], 0), ([3; 32], 1), ([3; 32], 0)])
+                .await
+                .unwrap(),
+            vec![None, Some(spend), None]
+        );
+    }
+
+    fn test_block(n: u8) -> BlockInfo {
+        BlockInfo {
+            hash: [n; 32],
+            prev_hash: [n.wrapping_sub(1); 32],
+        }
+    }
+
+    /// A block with two outputs, spending the first output of its parent.
+    fn test_indexed_block(height: u8) -> IndexedBlock {
+        IndexedBlock {
+            height: height as u64,
+            info: test_block(height),
+            utxos: vec![test_utxo(height, 0), test_utxo(height, 1)],
+            spends: vec![(
+                [height.wrapping_sub(1); 32],
+                0,
+                SpentInfo {
+                    height: height as u64,
+                    txid: [height; 32],
+                },
+            )],
+            filter: BlockFilter {
+                block_hash: [height; 32],
+                content: vec![0],
+            },
+        }
+    }
+
+    async fn test_rollback_conformance<S: TestStorage>() {
+        let store = S::new_for_test();
+        assert_eq!(store.tip().await.unwrap(), None);
+
+        for height in 1..=4 {
+            store.add_block(test_indexed_block(height)).await.unwrap();
+        }
+        assert_eq!(store.tip().await.unwrap(), Some((4, test_block(4))));
+
+        store.rollback_to(2).await.unwrap();
+
+        assert_eq!(store.tip().await.unwrap(), Some((2, test_block(2))));
+        assert_eq!(store.get_block_info(2).await.unwrap(), Some(test_block(2)));
+        assert_eq!(store.get_block_info(3).await.unwrap(), None);
+        assert_eq!(store.query_utxos(2).await.unwrap().len(), 2);
+        assert!(store.query_utxos(3).await.unwrap().is_empty());
+        assert!(store.query_utxos(4).await.unwrap().is_empty());
+        assert_eq!(store.get_utxo([3; 32], 0).await.unwrap(), None);
+        assert_eq!(
+            store.get_utxo([2; 32], 1).await.unwrap(),
+            Some((2, test_utxo(2, 1)))
+        );
+
+        // Filters of rolled back blocks are forgotten
+        assert_eq!(
+            store.get_filter(2).await.unwrap(),
+            Some(test_indexed_block(2).filter)
+        );
+        assert_eq!(store.get_filter(3).await.unwrap(), None);
+        assert_eq!(
+            store.get_filters(0, 9).await.unwrap(),
+            vec![
+                (1, test_indexed_block(1).filter),
+                (2, test_indexed_block(2).filter)
+            ]
+        );
+        assert!(store.get_filters(3, 9).await.unwrap().is_empty());
+
+        // Spends in rolled back blocks are forgotten
+        assert_eq!(
+            store.get_spend([1; 32], 0).await.unwrap(),
+            Some(test_indexed_block(2).spends[0].2)
+        );
+        assert_eq!(store.get_spend([2; 32], 0).await.unwrap(), None);
+
+        // The new branch can be indexed on top of the fork point
+        store
+            .add_block(IndexedBlock {
+                info: test_block(9),
+                utxos: vec![test_utxo(9, 0)],
+                ..test_indexed_block(3)
+            })
+            .await
+            .unwrap();
+        assert_eq!(store.tip().await.unwrap(), Some((3, test_block(9))));
+        assert_eq!(store.query_utxos(3).await.unwrap(), vec![test_utxo(9, 0)]);
+    }
+
+    async fn test_add_block_conformance<S: TestStorage>() {
+        let store = S::new_for_test();
+        assert_eq!(store.index_state().await.unwrap(), None);
+
+        for height in 1..=3 {
+            store.add_block(test_indexed_block(height)).await.unwrap();
+        }
+
+        let block = test_indexed_block(2);
+        assert_eq!(sorted(store.query_utxos(2).await.unwrap()), block.utxos);
+        assert_eq!(store.get_block_info(2).await.unwrap(), Some(block.info));
+        assert_eq!(store.get_filter(2).await.unwrap(), Some(block.filter));
+        assert_eq!(
+            store.get_spend([1; 32], 0).await.unwrap(),
+            Some(block.spends[0].2)
+        );
+        // Spends of outputs that were never indexed are not recorded
+        assert_eq!(store.get_spend([0; 32], 0).await.unwrap(), None);
+        assert_eq!(
+            store.index_state().await.unwrap(),
+            Some(IndexState {
+                height: 3,
+                block_hash: [3; 32],
+            })
+        );
+
+        // Writing a block again replaces its spends too
+        store
+            .add_block(IndexedBlock {
+                spends: vec![],
+                ..test_indexed_block(3)
+            })
+            .await
+            .unwrap();
+        assert_eq!(store.get_spend([2; 32], 0).await.unwrap(), None);
+        assert_eq!(
+            store.get_spend([1; 32], 0).await.unwrap(),
+            Some(block.spends[0].2)
+        );
+
+        // Replacing a block also forgets later spends of the outputs it drops
+        store.add_block(test_indexed_block(3)).await.unwrap();
+        assert!(store.get_spend([2; 32], 0).await.unwrap().is_some());
+        store
+            .add_block(IndexedBlock {
+                utxos: vec![test_utxo(9, 0)],
+                ..test_indexed_block(2)
+            })
+            .await
+            .unwrap();
+        assert_eq!(store.get_spend([2; 32], 0).await.unwrap(), None);
+        assert_eq!(
+            store.get_spend([1; 32], 0).await.unwrap(),
+            Some(block.spends[0].2)
+        );
+
+        // A whole block can be written at once, replacing what was there
+        let block_5 = vec![test_utxo(6, 0), test_utxo(6, 1), test_utxo(7, 0)];
+        let indexed_block_5 = |utxos| IndexedBlock {
+            height: 5,
+            info: test_block(5),
+            utxos,
+            ..Default::default()
+        };
+        store
+            .add_block(indexed_block_5(vec![test_utxo(8, 0)]))
+            .await
+            .unwrap();
+        store
+            .add_block(indexed_block_5(block_5.clone()))
+            .await
+            .unwrap();
+        assert_eq!(sorted(store.query_utxos(5).await.unwrap()), block_5);
+        assert_eq!(store.get_utxo([8; 32], 0).await.unwrap(), None);
+        assert_eq!(
+            store.get_utxo([7; 32], 0).await.unwrap(),
+            Some((5, test_utxo(7, 0)))
+        );
+        assert_eq!(
+            sorted(store.query_utxos(3).await.unwrap()),
+            test_indexed_block(3).utxos
+        );
+        assert_eq!(
+            store.index_state().await.unwrap(),
+            Some(IndexState {
+                height: 5,
+                block_hash: [5; 32],
+            })
+        );
+
+        // Rolling back moves the index state with it
+        store.rollback_to(1).await.unwrap();
+        assert_eq!(
+            store.index_state().await.unwrap(),
+            Some(IndexState {
+                height: 1,
+                block_hash: [1; 32],
+            })
+        );
+        assert_eq!(store.get_spend([1; 32], 0).await.unwrap(), None);
+        store.rollback_to(0).await.unwrap();
+        assert_eq!(store.index_state().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_mdbx_database_client_encryption() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        let key_dir = tempdir().unwrap();
+        let key_file = key_dir.path().join("master.key");
+        let new_key_file = key_dir.path().join("new_master.key");
+        let key = || MasterKey::load_or_create(&key_file).unwrap();
+        let new_key = || MasterKey::load_or_create(&new_key_file).unwrap();
+        let client_data = ClientData {
+            b_scan: Arc::new(ScanSecret::from([7; 32])),
+            ..test_client_data()
+        };
+        let found = test_utxo(9, 0);
+        let delivery = WebhookDelivery {
+            id: "delivery".to_string(),
+            client_id: "client".to_string(),
+            url: "http://127.0.0.1:1/hook".to_string(),
+            body: "{}".to_string(),
+            signature: "00".to_string(),
+            attempts: 0,
+            next_attempt: 0,
+        };
+        {
+            let db = MdbxDatabase::new(path.clone(), key()).unwrap();
+            db.store_client_data("client", client_data.clone())
+                .await
+                .unwrap();
+            db.add_found_outputs("client", 1, vec![matched(found.clone(), None)])
+                .await
+                .unwrap();
+            db.queue_webhook(delivery.clone()).await.unwrap();
+        }
+
+        // Neither the scan key nor the client's outputs are stored in the clear
+        let raw = std::fs::read(path.join("mdbx.dat")).unwrap();
+        assert!(!raw.windows(32).any(|window| window == [7; 32]));
+        assert!(!raw.windows(32).any(|window| window == found.txid));
+        assert!(!raw
+            .windows(delivery.url.len())
+            .any(|window| window == delivery.url.as_bytes()));
+
+        // A wrong key is refused when opening the database
+        assert!(matches!(
+            MdbxDatabase::new(path.clone(), MasterKey::generate()),
+            Err(Error::WrongMasterKey)
+        ));
+
+        // After rotation only the new key opens the database
+        {
+            let db = MdbxDatabase::new(path.clone(), key()).unwrap();
+            assert_eq!(db.rotate_master_key(new_key()).unwrap(), 1);
+            let rotated = db.get_client_data("client").await.unwrap();
+            assert_eq!(rotated.b_scan, client_data.b_scan);
+        }
+        assert!(matches!(
+            MdbxDatabase::new(path.clone(), key()),
+            Err(Error::WrongMasterKey)
+        ));
+        let db = MdbxDatabase::new(path, new_key()).unwrap();
+        let reopened = db.get_client_data("client").await.unwrap();
+        assert_eq!(reopened.b_scan, client_data.b_scan);
+        let found_outputs = db.get_found_outputs("client").await.unwrap();
+        assert_eq!(found_outputs.len(), 1);
+        assert_eq!(found_outputs[0].utxo, found);
+        assert_eq!(db.pending_webhooks().await.unwrap(), vec![delivery]);
+
+        // Found outputs moved to the new key can still be rolled back
+        assert_eq!(db.rollback_found_outputs(0).await.unwrap().len(), 1);
+
+        // Client ids too long for the key's length prefix are refused
+        assert!(matches!(
+            db.add_found_outputs(&"c".repeat(256), 1, vec![matched(found, None)])
+                .await,
+            Err(Error::InvalidInput(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_mdbx_database_open_creates_key_only_without_clients() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        let key_dir = tempdir().unwrap();
+        let key_file = key_dir.path().join("master.key");
+
+        // A key is created for a database without clients
+        {
+            let db = MdbxDatabase::open(path.clone(), &key_file).unwrap();
+            assert!(key_file.exists());
+            db.store_client_data("client", test_client_data())
+                .await
+                .unwrap();
+        }
+        MdbxDatabase::open(path.clone(), &key_file).unwrap();
+
+        // Once a client is registered, a missing key file is an error
+        std::fs::remove_file(&key_file).unwrap();
+        assert!(matches!(
+            MdbxDatabase::open(path.clone(), &key_file),
+            Err(Error::InvalidInput(_))
+        ));
+        assert!(!key_file.exists());
+    }
+
+    #[tokio::test]
+    async fn test_mdbx_database_encrypts_legacy_clients() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        let client_data = ClientData {
+            b_scan: Arc::new(ScanSecret::from([7; 32])),
+            ..test_client_data()
+        };
+        // Before encryption a record held only the receiver and the scan key
+        mdbx::write_plaintext_client(
+            path.clone(),
+            "client",
+            bincode::serialize(&(&client_data.receiver, &client_data.b_scan)).unwrap(),
+        )
+        .unwrap();
+
+        // The plaintext record is sealed when the database is first opened
+        // with a master key
+        let key_dir = tempdir().unwrap();
+        let key_file = key_dir.path().join("master.key");
+        {
+            let db = MdbxDatabase::new(path.clone(), MasterKey::load_or_create(&key_file).unwrap())
+                .unwrap();
+            let migrated = db.get_client_data("client").await.unwrap();
+            assert_eq!(migrated.b_scan, client_data.b_scan);
+            assert_eq!(
+                migrated.receiver.get_receiving_address(),
+                client_data.receiver.get_receiving_address()
+            );
+            assert_eq!(migrated.info, ClientInfo::default());
+            assert_eq!(migrated.last_scanned_height, None);
+
+            // Records written now use the current layout
+            db.update_client("client", client_data.clone())
+                .await
+                .unwrap();
+            let updated = db.get_client_data("client").await.unwrap();
+            assert_eq!(updated.info, client_data.info);
+        }
+
+        // The migration only runs once
+        let db = MdbxDatabase::new(path, MasterKey::load_or_create(&key_file).unwrap()).unwrap();
+        let reopened = db.get_client_data("client").await.unwrap();
+        assert_eq!(reopened.b_scan, client_data.b_scan);
+    }
+
+    async fn test_failed_scans_conformance<S: TestStorage>() {
+        let store = S::new_for_test();
+        assert!(store.failed_scans().await.unwrap().is_empty());
+
+        store.add_failed_scan("b", 3).await.unwrap();
+        store.add_failed_scan("a", 2).await.unwrap();
+        store.add_failed_scan("a", 3).await.unwrap();
+        // Recording a failure twice keeps one
+        store.add_failed_scan("a", 2).await.unwrap();
+        let mut failed = store.failed_scans().await.unwrap();
+        assert_eq!(failed[0], (2, "a".to_string()));
+        failed.sort();
+        assert_eq!(
+            failed,
+            vec![
+                (2, "a".to_string()),
+                (3, "a".to_string()),
+                (3, "b".to_string())
+            ]
+        );
+
+        store.remove_failed_scan("a", 3).await.unwrap();
+        store.remove_failed_scan("unknown", 3).await.unwrap();
+        assert_eq!(
+            store.failed_scans().await.unwrap(),
+            vec![(2, "a".to_string()), (3, "b".to_string())]
+        );
+
+        // Failures in rolled back blocks are forgotten
+        store.rollback_failed_scans(2).await.unwrap();
+        assert_eq!(
+            store.failed_scans().await.unwrap(),
+            vec![(2, "a".to_string())]
+        );
     }
 
     #[tokio::test]
@@ -101,4 +850,44 @@ mod tests {
     async fn test_mdbx_database() {
         test_storage_implementation::<MdbxDatabase>().await;
     }
+
+    #[tokio::test]
+    async fn test_memory_store_utxo_conformance() {
+        test_utxo_store_conformance::<MemoryStore>().await;
+    }
+
+    #[tokio::test]
+    async fn test_mdbx_database_utxo_conformance() {
+        test_utxo_store_conformance::<MdbxDatabase>().await;
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_rollback_conformance() {
+        test_rollback_conformance::<MemoryStore>().await;
+    }
+
+    #[tokio::test]
+    async fn test_mdbx_database_rollback_conformance() {
+        test_rollback_conformance::<MdbxDatabase>().await;
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_add_block_conformance() {
+        test_add_block_conformance::<MemoryStore>().await;
+    }
+
+    #[tokio::test]
+    async fn test_mdbx_database_add_block_conformance() {
+        test_add_block_conformance::<MdbxDatabase>().await;
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_failed_scans_conformance() {
+        test_failed_scans_conformance::<MemoryStore>().await;
+    }
+
+    #[tokio::test]
+    async fn test_mdbx_database_failed_scans_conformance() {
+        test_failed_scans_conformance::<MdbxDatabase>().await;
+    }
 }
@@ -0,0 +1,550 @@
+//! BIP352 input tweak computation.
+//!
+//! This only depends on a transaction and the outputs it spends, so it can be
+//! shared by every block source the indexer reads from.
+
+use crate::{Error, Result};
+use bitcoin::hashes::{hash160, sha256, Hash, HashEngine};
+use bitcoin::{Script, Transaction, TxIn, TxOut};
+use once_cell::sync::Lazy;
+use silentpayments::secp256k1::{Parity, PublicKey, Scalar, Secp256k1, VerifyOnly, XOnlyPublicKey};
+
+static SECP: Lazy<Secp256k1<VerifyOnly>> = Lazy::new(Secp256k1::verification_only);
+
+/// The NUMS point H from BIP341. Taproot script path spends with this
+/// internal key are not eligible.
+const NUMS_H: [u8; 32] = [
+    0x50, 0x92, 0x9b, 0x74, 0xc1, 0xa0, 0x49, 0x54, 0xb7, 0x8b, 0x4b, 0x60, 0x35, 0xe9, 0x7a, 0x5e,
+    0x07, 0x8a, 0x5a, 0x0f, 0x28, 0xec, 0x96, 0xd5, 0x47, 0xbf, 0xee, 0x9a, 0xce, 0x80, 0x3a, 0xc0,
+];
+
+const INPUTS_TAG: &[u8] = b"BIP0352/Inputs";
+
+/// Computes the input tweak `input_hash · A` of a transaction, where `A` is
+/// the sum of its eligible input public keys.
+///
+/// `prevouts` must hold the output spent by each input, in input order.
+/// Returns `None` if the transaction is not eligible for silent payments.
+pub fn input_tweak(tx: &Transaction, prevouts: &[TxOut]) -> Result<Option<[u8; 33]>> {
+    if tx.input.len() != prevouts.len() {
+        return Err(Error::InvalidInput(format!(
+            "transaction has {} inputs but {} prevouts",
+            tx.input.len(),
+            prevouts.len()
+        )));
+    }
+    if tx.is_coinbase() || !tx.output.iter().any(|o| o.script_pubkey.is_p2tr()) {
+        return Ok(None);
+    }
+    // Spending an output with an unknown segwit version makes the whole
+    // transaction ineligible, so that future upgrades can define new rules
+    if prevouts.iter().any(|p| is_future_witness(&p.script_pubkey)) {
+        return Ok(None);
+    }
+
+    let input_pubkeys: Vec<PublicKey> = tx
+        .input
+        .iter()
+        .zip(prevouts)
+        .filter_map(|(txin, prevout)| input_pubkey(txin, &prevout.script_pubkey))
+        .collect();
+    if input_pubkeys.is_empty() {
+        return Ok(None);
+    }
+    let input_pubkeys: Vec<&PublicKey> = input_pubkeys.iter().collect();
+    // The keys can sum to the point at infinity
+    let Ok(a_sum) = PublicKey::combine_keys(&input_pubkeys) else {
+        return Ok(None);
+    };
+
+    let smallest_outpoint = tx
+        .input
+        .iter()
+        .map(serialize_outpoint)
+        .min()
+        .expect("transaction has inputs");
+    let input_hash = tagged_hash(
+        INPUTS_TAG,
+        &[&smallest_outpoint[..], &a_sum.serialize()[..]],
+    );
+    let Ok(input_hash) = Scalar::from_be_bytes(input_hash) else {
+        return Ok(None);
+    };
+
+    Ok(Some(a_sum.mul_tweak(&*SECP, &input_hash)?.serialize()))
+}
+
+/// Extracts the public key of an eligible input, per the BIP352 rules for
+/// P2TR, P2WPKH, P2SH-P2WPKH and P2PKH.
+pub fn input_pubkey(txin: &TxIn, prevout: &Script) -> Option<PublicKey> {
+    let spk = prevout.as_bytes();
+    if prevout.is_p2tr() {
+        let mut stack: Vec<&[u8]> = txin.witness.iter().collect();
+        // Drop the annex
+        if stack.len() > 1 && stack.last().is_some_and(|e| e.first() == Some(&0x50)) {
+            stack.pop();
+        }
+        // Script path spends reveal the internal key in the control block
+        if stack.len() > 1 {
+            let control_block = stack.last()?;
+            if control_block.get(1..33)? == NUMS_H {
+                return None;
+            }
+        }
+        let output_key = XOnlyPublicKey::from_slice(&spk[2..34]).ok()?;
+        Some(PublicKey::from_x_only_public_key(output_key, Parity::Even))
+    } else if prevout.is_p2wpkh() {
+        compressed_pubkey(txin.witness.last()?)
+    } else if prevout.is_p2sh() {
+        // The script sig must be a single push of a P2WPKH redeem script
+        let redeem_script = txin.script_sig.as_bytes().get(1..)?;
+        if txin.script_sig.as_bytes().first() != Some(&22)
+            || !Script::from_bytes(redeem_script).is_p2wpkh()
+        {
+            return None;
+        }
+        compressed_pubkey(txin.witness.last()?)
+    } else if prevout.is_p2pkh() {
+        // The public key is the last 33 byte window of the script sig that
+        // hashes to the key hash, which also covers malleated script sigs
+        let key_hash = &spk[3..23];
+        let script_sig = txin.script_sig.as_bytes();
+        (33..=script_sig.len())
+            .rev()
+            .map(|end| &script_sig[end - 33..end])
+            .find(|candidate| hash160::Hash::hash(candidate).as_byte_array() == key_hash)
+            .and_then(compressed_pubkey)
+    } else {
+        None
+    }
+}
+
+fn compressed_pubkey(bytes: &[u8]) -> Option<PublicKey> {
+    if bytes.len() != 33 {
+        return None;
+    }
+    PublicKey::from_slice(bytes).ok()
+}
+
+/// Returns true for segwit outputs with a version above 1.
+fn is_future_witness(script: &Script) -> bool {
+    script
+        .witness_version()
+        .is_some_and(|version| version.to_num() > 1)
+}
+
+/// Serializes an outpoint as txid (internal byte order) followed by the
+/// little-endian output index.
+fn serialize_outpoint(txin: &TxIn) -> [u8; 36] {
+    let mut buf = [0u8; 36];
+    buf[..32].copy_from_slice(txin.previous_output.txid.as_byte_array());
+    buf[32..].copy_from_slice(&txin.previous_output.vout.to_le_bytes());
+    buf
+}
+
+fn tagged_hash(tag: &[u8], data: &[&[u8]]) -> [u8; 32] {
+    let tag_hash = sha256::Hash::hash(tag);
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag_hash.as_byte_array());
+    engine.input(tag_hash.as_byte_array());
+    for chunk in data {
+        engine.input(chunk);
+    }
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{absolute, transaction, Amount, OutPoint, ScriptBuf, Sequence, Txid, Witness};
+    use serde::Deserialize;
+    use silentpayments::secp256k1::SecretKey;
+    use std::str::FromStr;
+
+    const TXID_1: [u8; 32] = [0x11; 32];
+    const TXID_3: [u8; 32] = [0x22; 32];
+
+    fn txid_2() -> [u8; 32] {
+        let mut txid = [0x0a; 32];
+        txid[31] = 0xff;
+        txid
+    }
+
+    /// Private key `n` of the sending inputs.
+    fn secret_key(n: u8) -> SecretKey {
+        SecretKey::from_slice(&[n; 32]).unwrap()
+    }
+
+    /// Compressed public key of `secret_key(n)`, hex encoded.
+    fn public_key(n: u8) -> String {
+        PublicKey::from_secret_key(&Secp256k1::signing_only(), &secret_key(n)).to_string()
+    }
+
+    /// The input tweak as the sender computes it from the private keys of
+    /// the eligible inputs, `(n, is_taproot)`: `input_hash · a` times G,
+    /// where `a` is the sum of the keys with taproot keys negated if their
+    /// point has an odd y coordinate.
+    fn sender_tweak(keys: &[(u8, bool)], smallest_outpoint: ([u8; 32], u32)) -> String {
+        let secp = Secp256k1::signing_only();
+        let a_sum = keys
+            .iter()
+            .map(|&(n, taproot)| {
+                let key = secret_key(n);
+                if taproot && key.x_only_public_key(&secp).1 == Parity::Odd {
+                    key.negate()
+                } else {
+                    key
+                }
+            })
+            .reduce(|sum, key| sum.add_tweak(&Scalar::from(key)).unwrap())
+            .unwrap();
+        let (txid, vout) = smallest_outpoint;
+        let input_hash = tagged_hash(
+            INPUTS_TAG,
+            &[
+                &txid[..],
+                &vout.to_le_bytes()[..],
+                &PublicKey::from_secret_key(&secp, &a_sum).serialize()[..],
+            ],
+        );
+        let tweak = a_sum
+            .mul_tweak(&Scalar::from_be_bytes(input_hash).unwrap())
+            .unwrap();
+        PublicKey::from_secret_key(&secp, &tweak).to_string()
+    }
+
+    fn key(hex_key: &str) -> Vec<u8> {
+        hex::decode(hex_key).unwrap()
+    }
+
+    fn key_hash(bytes: &[u8]) -> Vec<u8> {
+        hash160::Hash::hash(bytes).to_byte_array().to_vec()
+    }
+
+    fn input(txid: [u8; 32], vout: u32, script_sig: Vec<u8>, witness: Vec<Vec<u8>>) -> TxIn {
+        TxIn {
+            previous_output: OutPoint::new(Txid::from_byte_array(txid), vout),
+            script_sig: ScriptBuf::from_bytes(script_sig),
+            sequence: Sequence::MAX,
+            witness: Witness::from_slice(&witness),
+        }
+    }
+
+    fn prevout(script: Vec<u8>) -> TxOut {
+        TxOut {
+            value: Amount::from_sat(100_000),
+            script_pubkey: ScriptBuf::from_bytes(script),
+        }
+    }
+
+    fn p2wpkh(txid: [u8; 32], vout: u32, pubkey: &str) -> (TxIn, TxOut) {
+        let pubkey = key(pubkey);
+        let script = [vec![0x00, 0x14], key_hash(&pubkey)].concat();
+        (
+            input(txid, vout, vec![], vec![vec![0x30; 71], pubkey]),
+            prevout(script),
+        )
+    }
+
+    fn p2pkh(txid: [u8; 32], vout: u32, pubkey: Vec<u8>) -> (TxIn, TxOut) {
+        let script = [vec![0x76, 0xa9, 0x14], key_hash(&pubkey), vec![0x88, 0xac]].concat();
+        let script_sig = [vec![71], vec![0x30; 71], vec![pubkey.len() as u8], pubkey].concat();
+        (input(txid, vout, script_sig, vec![]), prevout(script))
+    }
+
+    fn p2sh_p2wpkh(txid: [u8; 32], vout: u32, pubkey: &str) -> (TxIn, TxOut) {
+        let pubkey = key(pubkey);
+        let redeem_script = [vec![0x00, 0x14], key_hash(&pubkey)].concat();
+        let script = [vec![0xa9, 0x14], key_hash(&redeem_script), vec![0x87]].concat();
+        let script_sig = [vec![redeem_script.len() as u8], redeem_script].concat();
+        (
+            input(txid, vout, script_sig, vec![vec![0x30; 71], pubkey]),
+            prevout(script),
+        )
+    }
+
+    fn p2tr_script(pubkey: &str) -> Vec<u8> {
+        [vec![0x51, 0x20], key(pubkey)[1..].to_vec()].concat()
+    }
+
+    fn p2tr_key_path(txid: [u8; 32], vout: u32, pubkey: &str, annex: bool) -> (TxIn, TxOut) {
+        let mut witness = vec![vec![0x01; 64]];
+        if annex {
+            witness.push(vec![0x50, 0x00]);
+        }
+        (
+            input(txid, vout, vec![], witness),
+            prevout(p2tr_script(pubkey)),
+        )
+    }
+
+    fn p2tr_script_path(
+        txid: [u8; 32],
+        vout: u32,
+        pubkey: &str,
+        internal_key: &[u8],
+    ) -> (TxIn, TxOut) {
+        let control_block = [vec![0xc0], internal_key.to_vec()].concat();
+        let witness = vec![vec![0x01; 64], vec![0xac], control_block];
+        (
+            input(txid, vout, vec![], witness),
+            prevout(p2tr_script(pubkey)),
+        )
+    }
+
+    fn tweak_of(inputs: Vec<(TxIn, TxOut)>) -> Option<String> {
+        let (input, prevouts): (Vec<TxIn>, Vec<TxOut>) = inputs.into_iter().unzip();
+        let tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input,
+            output: vec![prevout(p2tr_script(&public_key(6)))],
+        };
+        input_tweak(&tx, &prevouts).unwrap().map(hex::encode)
+    }
+
+    fn assert_tweak(inputs: Vec<(TxIn, TxOut)>, expected: String) {
+        assert_eq!(tweak_of(inputs), Some(expected));
+    }
+
+    #[test]
+    fn test_p2wpkh() {
+        assert_tweak(
+            vec![p2wpkh(TXID_1, 0, &public_key(1))],
+            sender_tweak(&[(1, false)], (TXID_1, 0)),
+        );
+    }
+
+    #[test]
+    fn test_p2pkh_and_p2tr_key_path() {
+        assert_tweak(
+            vec![
+                p2pkh(txid_2(), 3, key(&public_key(2))),
+                p2tr_key_path(TXID_1, 1, &public_key(3), false),
+            ],
+            sender_tweak(&[(2, false), (3, true)], (txid_2(), 3)),
+        );
+    }
+
+    #[test]
+    fn test_smallest_outpoint_uses_serialized_vout() {
+        // vout 256 serializes as 00010000, which sorts before vout 1
+        let expected = sender_tweak(&[(1, false), (4, false)], (TXID_3, 256));
+        assert_tweak(
+            vec![
+                p2wpkh(TXID_3, 1, &public_key(1)),
+                p2wpkh(TXID_3, 256, &public_key(4)),
+            ],
+            expected.clone(),
+        );
+        // Input order does not matter
+        assert_tweak(
+            vec![
+                p2wpkh(TXID_3, 256, &public_key(4)),
+                p2wpkh(TXID_3, 1, &public_key(1)),
+            ],
+            expected,
+        );
+    }
+
+    #[test]
+    fn test_p2sh_p2wpkh() {
+        assert_tweak(
+            vec![p2sh_p2wpkh(txid_2(), 0, &public_key(4))],
+            sender_tweak(&[(4, false)], (txid_2(), 0)),
+        );
+    }
+
+    #[test]
+    fn test_nums_script_path_is_skipped() {
+        // The NUMS input is not summed, but its outpoint still counts
+        assert_tweak(
+            vec![
+                p2wpkh(TXID_1, 0, &public_key(1)),
+                p2tr_script_path(txid_2(), 5, &public_key(5), &NUMS_H),
+            ],
+            sender_tweak(&[(1, false)], (txid_2(), 5)),
+        );
+        assert_eq!(
+            tweak_of(vec![p2tr_script_path(txid_2(), 5, &public_key(5), &NUMS_H)]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_p2tr_annex_and_script_path() {
+        assert_tweak(
+            vec![
+                p2tr_key_path(TXID_3, 0, &public_key(5), true),
+                p2tr_script_path(TXID_3, 2, &public_key(3), &key(&public_key(6))[1..]),
+            ],
+            sender_tweak(&[(5, true), (3, true)], (TXID_3, 0)),
+        );
+    }
+
+    #[test]
+    fn test_uncompressed_p2pkh_is_skipped() {
+        let pubkey = PublicKey::from_slice(&key(&public_key(2))).unwrap();
+        let uncompressed = pubkey.serialize_uncompressed().to_vec();
+        assert_eq!(tweak_of(vec![p2pkh(TXID_1, 0, uncompressed)]), None);
+    }
+
+    #[test]
+    fn test_future_witness_version_is_ineligible() {
+        let (txin, _) = p2tr_key_path(TXID_3, 0, &public_key(5), false);
+        let v2 = [vec![0x52, 0x20], vec![0x33; 32]].concat();
+        assert_eq!(
+            tweak_of(vec![p2wpkh(TXID_1, 0, &public_key(1)), (txin, prevout(v2))]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_inputs_summing_to_infinity() {
+        let negated = PublicKey::from_slice(&key(&public_key(1)))
+            .unwrap()
+            .negate(&*SECP);
+        let (txin, txout) = p2wpkh(txid_2(), 0, &negated.to_string());
+        assert_eq!(
+            tweak_of(vec![p2wpkh(TXID_1, 0, &public_key(1)), (txin, txout)]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_no_taproot_outputs() {
+        let (txin, txout) = p2wpkh(TXID_1, 0, &public_key(1));
+        let tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![txin],
+            output: vec![txout.clone()],
+        };
+        assert_eq!(input_tweak(&tx, &[txout]).unwrap(), None);
+    }
+
+    /// `send_and_receive_test_vectors.json` from the BIP352 directory of
+    /// the bitcoin/bips repository, copied verbatim.
+    const BIP352_VECTORS: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/send_and_receive_test_vectors.json"
+    );
+
+    #[derive(Deserialize)]
+    struct TestCase {
+        comment: String,
+        receiving: Vec<ReceivingCase>,
+    }
+
+    #[derive(Deserialize)]
+    struct ReceivingCase {
+        given: ReceivingGiven,
+        expected: ReceivingExpected,
+    }
+
+    #[derive(Deserialize)]
+    struct ReceivingGiven {
+        vin: Vec<VectorInput>,
+        outputs: Vec<String>,
+    }
+
+    #[derive(Deserialize)]
+    struct ReceivingExpected {
+        tweak: Option<String>,
+        #[serde(default)]
+        input_pub_keys: Option<Vec<String>>,
+    }
+
+    #[derive(Deserialize)]
+    struct VectorInput {
+        txid: String,
+        vout: u32,
+        #[serde(rename = "scriptSig")]
+        script_sig: String,
+        txinwitness: String,
+        prevout: VectorPrevout,
+    }
+
+    #[derive(Deserialize)]
+    struct VectorPrevout {
+        #[serde(rename = "scriptPubKey")]
+        script_pubkey: VectorScript,
+    }
+
+    #[derive(Deserialize)]
+    struct VectorScript {
+        hex: String,
+    }
+
+    fn vector_input(vin: &VectorInput) -> (TxIn, TxOut) {
+        let witness = match vin.txinwitness.as_str() {
+            "" => Witness::new(),
+            hex_witness => {
+                bitcoin::consensus::deserialize(&hex::decode(hex_witness).unwrap()).unwrap()
+            }
+        };
+        let txin = TxIn {
+            previous_output: OutPoint::new(Txid::from_str(&vin.txid).unwrap(), vin.vout),
+            script_sig: ScriptBuf::from_bytes(hex::decode(&vin.script_sig).unwrap()),
+            sequence: Sequence::MAX,
+            witness,
+        };
+        (
+            txin,
+            prevout(hex::decode(&vin.prevout.script_pubkey.hex).unwrap()),
+        )
+    }
+
+    /// Checks the input tweak, and the input public keys where listed, of
+    /// every receiving case that lists a tweak.
+    #[test]
+    fn test_bip352_vectors() {
+        let vectors = std::fs::read_to_string(BIP352_VECTORS).unwrap_or_else(|e| {
+            panic!(
+                "{}: {}, copy it from bip-0352 in the bitcoin/bips repository",
+                BIP352_VECTORS, e
+            )
+        });
+        let cases: Vec<TestCase> = serde_json::from_str(&vectors).unwrap();
+        let mut checked = 0;
+        for case in &cases {
+            for receiving in &case.receiving {
+                let Some(expected) = &receiving.expected.tweak else {
+                    continue;
+                };
+                let (input, prevouts): (Vec<TxIn>, Vec<TxOut>) =
+                    receiving.given.vin.iter().map(vector_input).unzip();
+                if let Some(expected_keys) = &receiving.expected.input_pub_keys {
+                    let mut keys: Vec<String> = input
+                        .iter()
+                        .zip(&prevouts)
+                        .filter_map(|(txin, prevout)| input_pubkey(txin, &prevout.script_pubkey))
+                        .map(|key| key.to_string())
+                        .collect();
+                    let mut expected_keys = expected_keys.clone();
+                    keys.sort();
+                    expected_keys.sort();
+                    assert_eq!(keys, expected_keys, "{}", case.comment);
+                }
+                let output = receiving
+                    .given
+                    .outputs
+                    .iter()
+                    .map(|x_only| {
+                        prevout([vec![0x51, 0x20], hex::decode(x_only).unwrap()].concat())
+                    })
+                    .collect();
+                let tx = Transaction {
+                    version: transaction::Version::TWO,
+                    lock_time: absolute::LockTime::ZERO,
+                    input,
+                    output,
+                };
+                let tweak = input_tweak(&tx, &prevouts).unwrap().map(hex::encode);
+                assert_eq!(tweak.as_ref(), Some(expected), "{}", case.comment);
+                checked += 1;
+            }
+        }
+        assert!(checked > 0, "no receiving case lists a tweak");
+    }
+}